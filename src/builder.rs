@@ -0,0 +1,303 @@
+use std::{collections::HashMap, io, sync::Mutex, time::Duration};
+
+use ethercat_esi::EtherCatInfo;
+
+use crate::{
+    config::{
+        Config, EthercatConfig, InitialOutput, PdoAssignment, RegisterType, SlaveConfig,
+        WatchdogBehavior,
+    },
+    sdo::{PreOp, PreOpHook},
+    values::DataType,
+    ConfigurationPlan, DataLogging, EtherCatController,
+};
+
+/// Outputs written in the final cycle when the controller is stopped.
+#[derive(Debug, Clone, Default)]
+pub enum ShutdownBehavior {
+    /// Zeroes the whole output image.
+    Zero,
+    /// Keeps the last commanded outputs.
+    #[default]
+    Hold,
+    /// Writes the given `(slave_id, register, index, value)` and keeps the
+    /// others.
+    SafeValue(Vec<(u16, String, usize, Vec<u8>)>),
+}
+
+/// Where the slave descriptions come from.
+#[derive(Debug, Clone)]
+pub(crate) enum EsiSource {
+    File(String),
+    Info(Box<EtherCatInfo>),
+}
+
+#[derive(Debug)]
+pub struct EtherCatControllerBuilder {
+    pub(crate) esi: EsiSource,
+    pub(crate) master_id: u32,
+    pub(crate) cycle_period: Duration,
+    pub(crate) slaves: HashMap<u16, SlaveConfig>,
+    /// Slaves configured more than once, reported when opening.
+    pub(crate) duplicate_slaves: Vec<u16>,
+    pub(crate) pdo_assignments: Vec<PdoAssignment>,
+    pub(crate) initial_outputs: Vec<InitialOutput>,
+    pub(crate) watchdogs: HashMap<u16, WatchdogBehavior>,
+    pub(crate) register_types: Vec<RegisterType>,
+    pub(crate) data_logging: DataLogging,
+    pub(crate) shutdown_outputs: ShutdownBehavior,
+    pub(crate) on_preop: Option<PreOpHook>,
+    pub(crate) manual_cycle: bool,
+    pub(crate) lenient_writes: bool,
+    pub(crate) allow_input_writes: bool,
+    pub(crate) ready_timeout: Option<Duration>,
+    pub(crate) response_timeout: Option<Duration>,
+    pub(crate) ready_stable_cycles: u32,
+    pub(crate) histogram_edges: Option<Vec<Duration>>,
+    pub(crate) update_divisors: HashMap<u16, u32>,
+    pub(crate) label: Option<String>,
+}
+
+impl EtherCatControllerBuilder {
+    pub fn new(filename: &str, master_id: u32, cycle_period: Duration) -> Self {
+        Self::with_esi_source(
+            EsiSource::File(filename.to_string()),
+            master_id,
+            cycle_period,
+        )
+    }
+
+    /// Same as `new` with an already parsed ESI, e.g. embedded in the binary.
+    pub fn from_esi(esi: EtherCatInfo, master_id: u32, cycle_period: Duration) -> Self {
+        Self::with_esi_source(EsiSource::Info(Box::new(esi)), master_id, cycle_period)
+    }
+
+    fn with_esi_source(esi: EsiSource, master_id: u32, cycle_period: Duration) -> Self {
+        EtherCatControllerBuilder {
+            esi,
+            master_id,
+            cycle_period,
+            slaves: HashMap::new(),
+            duplicate_slaves: Vec::new(),
+            pdo_assignments: Vec::new(),
+            initial_outputs: Vec::new(),
+            watchdogs: HashMap::new(),
+            register_types: Vec::new(),
+            data_logging: DataLogging::default(),
+            shutdown_outputs: ShutdownBehavior::default(),
+            on_preop: None,
+            manual_cycle: false,
+            lenient_writes: false,
+            allow_input_writes: false,
+            ready_timeout: None,
+            response_timeout: None,
+            ready_stable_cycles: 1,
+            histogram_edges: None,
+            update_divisors: HashMap::new(),
+            label: None,
+        }
+    }
+
+    pub fn from_config(config: &Config, cycle_period: Duration) -> Self {
+        let mut builder = Self::new(
+            &config.ethercat.esi,
+            config.ethercat.master_id,
+            cycle_period,
+        );
+
+        for slave in &config.slaves {
+            if let Some(id) = slave.id() {
+                if builder.slaves.insert(id, slave.clone()).is_some() {
+                    builder.duplicate_slaves.push(id);
+                }
+            }
+        }
+
+        builder.ethercat_config(&config.ethercat)
+    }
+
+    /// Takes the settings of `ethercat_config` except its ESI path.
+    pub fn ethercat_config(mut self, ethercat_config: &EthercatConfig) -> Self {
+        self.master_id = ethercat_config.master_id;
+        self.pdo_assignments = ethercat_config.pdo_assignments.clone();
+        self.initial_outputs = ethercat_config.initial_outputs.clone();
+        self.watchdogs = ethercat_config.watchdogs.clone();
+        self.register_types = ethercat_config.register_types.clone();
+        self
+    }
+
+    /// Assigns the given PDOs to the sync manager of the slave instead of
+    /// the ones listed in the ESI.
+    pub fn pdo_assignment(mut self, slave_id: u16, sync_manager: u8, pdos: Vec<u16>) -> Self {
+        self.pdo_assignments.push(PdoAssignment {
+            slave_id,
+            sync_manager,
+            pdos,
+        });
+        self
+    }
+
+    /// Writes `value` to the register in the first cycle, before any other
+    /// write.
+    pub fn initial_output(
+        mut self,
+        slave_id: u16,
+        register: &str,
+        index: usize,
+        value: Vec<u8>,
+    ) -> Self {
+        self.initial_outputs.push(InitialOutput {
+            slave_id,
+            register: register.to_string(),
+            index,
+            value,
+        });
+        self
+    }
+
+    /// Overrides the data type given by the ESI for the register.
+    pub fn register_type(mut self, slave_id: u16, register: &str, data_type: DataType) -> Self {
+        self.register_types.push(RegisterType {
+            slave_id,
+            register: register.to_string(),
+            data_type,
+        });
+        self
+    }
+
+    pub fn watchdog(mut self, slave_id: u16, behavior: WatchdogBehavior) -> Self {
+        self.watchdogs.insert(slave_id, behavior);
+        self
+    }
+
+    pub fn data_logging(mut self, data_logging: DataLogging) -> Self {
+        self.data_logging = data_logging;
+        self
+    }
+
+    pub fn shutdown_outputs(mut self, shutdown_outputs: ShutdownBehavior) -> Self {
+        self.shutdown_outputs = shutdown_outputs;
+        self
+    }
+
+    /// Runs `hook` once the slaves are configured and in PREOP, before the
+    /// master is activated and brings them to SAFEOP then OP. This is where
+    /// the drive parameters that must be set before operation are written.
+    pub fn on_preop<F>(mut self, hook: F) -> Self
+    where
+        F: FnOnce(&mut PreOp) -> Result<(), io::Error> + Send + 'static,
+    {
+        self.on_preop = Some(PreOpHook(Mutex::new(Box::new(hook))));
+        self
+    }
+
+    /// Does not start the cyclic thread: each cycle is run by calling
+    /// `EtherCatController::step_cycle`, and the cycle period is ignored.
+    /// The waiting methods only return once cycles are stepped from another
+    /// thread.
+    pub fn manual_cycle(mut self, manual_cycle: bool) -> Self {
+        self.manual_cycle = manual_cycle;
+        self
+    }
+
+    /// Makes `set_pdo_registers` warn and write as many values as possible
+    /// instead of failing when the number of values does not match.
+    pub fn lenient_writes(mut self, lenient_writes: bool) -> Self {
+        self.lenient_writes = lenient_writes;
+        self
+    }
+
+    /// Lets writes to input registers through with a warning instead of
+    /// rejecting them. They are overwritten by the slave at the next cycle.
+    pub fn allow_input_writes(mut self, allow_input_writes: bool) -> Self {
+        self.allow_input_writes = allow_input_writes;
+        self
+    }
+
+    /// Makes `open` wait until the link is up and every slave is in OP, and
+    /// fail with the slaves that are not if it takes longer than `timeout`.
+    /// Ignored in manual cycle mode.
+    pub fn ready_timeout(mut self, timeout: Duration) -> Self {
+        self.ready_timeout = Some(timeout);
+        self
+    }
+
+    /// Only declares the master ready once the link was up for `cycles`
+    /// consecutive cycles, 1 by default.
+    pub fn ready_stable_cycles(mut self, cycles: u32) -> Self {
+        self.ready_stable_cycles = cycles.max(1);
+        self
+    }
+
+    /// Label appended to the log target of the controller, to tell apart the
+    /// logs of several controllers: `ethercat_controller::master<id>::<label>`.
+    pub fn label(mut self, label: &str) -> Self {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    /// Only applies the writes to the slave every `divisor` cycles, the ones
+    /// made in between waiting for the next of these cycles, in order. Meant
+    /// for slow slaves on a large bus. The inputs are still read and the
+    /// whole domain is still exchanged and published at every cycle: only
+    /// the writes are throttled. `flush_writes` waits for the deferred writes.
+    pub fn update_divisor(mut self, slave_id: u16, divisor: u32) -> Self {
+        self.update_divisors.insert(slave_id, divisor.max(1));
+        self
+    }
+
+    /// Upper edges of the buckets of `EtherCatController::timing_histogram`.
+    /// By default they are 1/4, 1/2, 3/4, 1 and 2 cycle periods.
+    pub fn timing_histogram_edges(mut self, edges: Vec<Duration>) -> Self {
+        self.histogram_edges = Some(edges);
+        self
+    }
+
+    /// Synchronous control: after publishing the inputs, each cycle waits up
+    /// to `timeout` for `EtherCatController::commit_writes` before sending,
+    /// so that writes computed from these inputs go out in the same cycle.
+    /// The timeout should leave enough of the cycle period for the send.
+    pub fn synchronous_control(mut self, timeout: Duration) -> Self {
+        self.response_timeout = Some(timeout);
+        self
+    }
+
+    /// Copy of the settings, without the PREOP hook which can only run once.
+    pub(crate) fn settings(&self) -> Self {
+        EtherCatControllerBuilder {
+            esi: self.esi.clone(),
+            master_id: self.master_id,
+            cycle_period: self.cycle_period,
+            slaves: self.slaves.clone(),
+            duplicate_slaves: self.duplicate_slaves.clone(),
+            pdo_assignments: self.pdo_assignments.clone(),
+            initial_outputs: self.initial_outputs.clone(),
+            watchdogs: self.watchdogs.clone(),
+            register_types: self.register_types.clone(),
+            data_logging: self.data_logging,
+            shutdown_outputs: self.shutdown_outputs.clone(),
+            on_preop: None,
+            manual_cycle: self.manual_cycle,
+            lenient_writes: self.lenient_writes,
+            allow_input_writes: self.allow_input_writes,
+            ready_timeout: self.ready_timeout,
+            response_timeout: self.response_timeout,
+            ready_stable_cycles: self.ready_stable_cycles,
+            histogram_edges: self.histogram_edges.clone(),
+            update_divisors: self.update_divisors.clone(),
+            label: self.label.clone(),
+        }
+    }
+
+    pub fn open(self) -> Result<EtherCatController, io::Error> {
+        EtherCatController::open_with(self)
+    }
+
+    /// Configures the slaves and registers the entries like `open`, but
+    /// releases the master instead of activating it. The PREOP hook is not
+    /// run, and the slaves are left in PREOP, where they were requested to be
+    /// configured.
+    pub fn dry_run(self) -> Result<ConfigurationPlan, io::Error> {
+        EtherCatController::dry_run_with(self)
+    }
+}
@@ -1,7 +1,9 @@
-use std::{error::Error, fs};
+use std::{collections::HashMap, error::Error, fs};
 
 use serde::{Deserialize, Serialize};
 
+use crate::values::DataType;
+
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Config {
     pub ethercat: EthercatConfig,
@@ -12,19 +14,94 @@ pub struct Config {
 pub struct EthercatConfig {
     pub master_id: u32,
     pub esi: String,
+    #[serde(default)]
+    pub pdo_assignments: Vec<PdoAssignment>,
+    #[serde(default)]
+    pub initial_outputs: Vec<InitialOutput>,
+    /// Data types overriding the ones of the ESI.
+    #[serde(default)]
+    pub register_types: Vec<RegisterType>,
+    /// Watchdog behavior of the output sync manager, by slave id.
+    #[serde(default)]
+    pub watchdogs: HashMap<u16, WatchdogBehavior>,
+}
+
+/// Data type of all the entries registered under the name.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegisterType {
+    pub slave_id: u16,
+    pub register: String,
+    pub data_type: DataType,
+}
+
+/// What the slave does with its outputs when the master stops refreshing
+/// them. How a drive then reacts (fault, quick stop...) is set by its own
+/// objects, e.g. 0x6007 for CiA 402 drives, which can be written in the
+/// PREOP hook.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WatchdogBehavior {
+    /// Keeps the setting of the slave.
+    #[default]
+    Default,
+    /// The sync manager watchdog triggers and the slave puts its outputs in
+    /// their safe state.
+    SafeState,
+    /// No watchdog: the outputs hold their last value.
+    Hold,
+}
+
+/// Value written to an output register in the first cycle, before any other
+/// write, so that the outputs are defined by the time the slaves reach OP.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InitialOutput {
+    pub slave_id: u16,
+    pub register: String,
+    #[serde(default)]
+    pub index: usize,
+    pub value: Vec<u8>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// PDOs to assign to a sync manager of a slave, replacing the ones listed in
+/// the ESI. Only the process data sync managers (2 for the outputs, 3 for the
+/// inputs) can be assigned.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PdoAssignment {
+    pub slave_id: u16,
+    pub sync_manager: u8,
+    pub pdos: Vec<u16>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum SlaveConfig {
     Epos(EposKind),
     Unknown,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+impl SlaveConfig {
+    pub fn id(&self) -> Option<u16> {
+        match self {
+            SlaveConfig::Epos(epos) => Some(epos.id),
+            SlaveConfig::Unknown => None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct EposKind {
     pub id: u16,
     pub encoder_resolution: u32,
     pub reduction: f32,
+    /// Motor rated torque in N.m, used to scale the torque actual value.
+    /// When left at 0 it is computed from the torque constant and the rated
+    /// current.
+    #[serde(default)]
+    pub rated_torque: f32,
+    /// Motor torque constant in N.m/A.
+    #[serde(default)]
+    pub torque_constant: f32,
+    /// Motor rated current in A.
+    #[serde(default)]
+    pub rated_current: f32,
 }
 
 impl Config {
@@ -0,0 +1,778 @@
+use std::{
+    collections::HashMap,
+    fmt, io,
+    ops::Range,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{channel, sync_channel, Receiver, SendError, Sender, SyncSender},
+        Arc, Condvar, Mutex, PoisonError, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use ethercat::{AlState, DomainIdx, Master, SlavePos};
+use ethercat_esi::EtherCatInfo;
+
+use crate::{
+    config::{PdoAssignment, WatchdogBehavior},
+    ethercat_controller::init_master_with_esi,
+    events::{BusEvent, EventBus},
+    logging::DataLogger,
+    metrics::{CycleHistogram, MasterCounters},
+    offsets::OffsetMap,
+    sdo::SdoAccess,
+    snapshot::Publisher,
+    ShutdownBehavior,
+};
+
+const SLAVE_STATE_POLL_PERIOD: Duration = Duration::from_millis(100);
+
+/// A value to copy in the domain image for the given slave.
+#[derive(Debug, Clone)]
+pub(crate) struct PdoWrite {
+    pub(crate) slave_id: u16,
+    pub(crate) range: Range<usize>,
+    pub(crate) value: Vec<u8>,
+    /// Bits of `value` to copy, for entries that do not span whole bytes.
+    pub(crate) mask: Option<Vec<u8>>,
+}
+
+impl PdoWrite {
+    pub(crate) fn new(slave_id: u16, range: Range<usize>, value: Vec<u8>) -> Self {
+        PdoWrite {
+            slave_id,
+            range,
+            value,
+            mask: None,
+        }
+    }
+
+    /// Copies the value in the image, or returns `false` without touching it
+    /// if the value does not fit.
+    pub(crate) fn apply(&self, data: &mut [u8]) -> bool {
+        let Some(target) = data
+            .get_mut(self.range.clone())
+            .filter(|target| target.len() == self.value.len())
+        else {
+            return false;
+        };
+        match &self.mask {
+            Some(mask) => {
+                for ((byte, value), mask) in target.iter_mut().zip(&self.value).zip(mask) {
+                    *byte = (*byte & !mask) | (value & mask);
+                }
+            }
+            None => target.copy_from_slice(&self.value),
+        }
+        true
+    }
+}
+
+/// What the cyclic task needs from the master and its domain.
+pub(crate) trait Bus: Send {
+    fn receive(&mut self) -> Result<(), io::Error>;
+    fn process(&mut self) -> Result<(), io::Error>;
+    fn working_counter(&mut self) -> Result<u32, io::Error>;
+    /// Domain image, as received, to be updated with the outputs.
+    fn data(&mut self) -> Result<&mut [u8], io::Error>;
+    /// Queues the domain and sends the frames.
+    fn send(&mut self) -> Result<(), io::Error>;
+    fn link_up(&mut self) -> Result<bool, io::Error>;
+    /// AL state of the slave, `None` if it is absent.
+    fn slave_state(&self, slave_pos: SlavePos) -> Option<AlState>;
+    fn deactivate(&mut self) -> Result<(), io::Error>;
+}
+
+pub(crate) struct MasterBus {
+    pub(crate) master: Master,
+    pub(crate) domain_idx: DomainIdx,
+}
+
+impl Bus for MasterBus {
+    fn receive(&mut self) -> Result<(), io::Error> {
+        Ok(self.master.receive()?)
+    }
+
+    fn process(&mut self) -> Result<(), io::Error> {
+        Ok(self.master.domain(self.domain_idx).process()?)
+    }
+
+    fn working_counter(&mut self) -> Result<u32, io::Error> {
+        Ok(self.master.domain(self.domain_idx).state()?.working_counter)
+    }
+
+    fn data(&mut self) -> Result<&mut [u8], io::Error> {
+        Ok(self.master.domain_data(self.domain_idx)?)
+    }
+
+    fn send(&mut self) -> Result<(), io::Error> {
+        self.master.domain(self.domain_idx).queue()?;
+        self.master.send()?;
+        Ok(())
+    }
+
+    fn link_up(&mut self) -> Result<bool, io::Error> {
+        Ok(self.master.state()?.link_up)
+    }
+
+    fn slave_state(&self, slave_pos: SlavePos) -> Option<AlState> {
+        self.master
+            .get_slave_info(slave_pos)
+            .ok()
+            .map(|info| info.al_state)
+    }
+
+    fn deactivate(&mut self) -> Result<(), io::Error> {
+        Ok(self.master.deactivate()?)
+    }
+}
+
+/// What opening the controller needs from the master before the cyclic task
+/// runs over a `Bus`.
+pub(crate) trait Configure {
+    /// Configures the slaves from the ESI and registers their entries in the
+    /// domain, returning the offsets and the slave names. The slaves are
+    /// requested in PREOP to be configured.
+    fn configure(
+        &mut self,
+        log_target: &str,
+        esi: &EtherCatInfo,
+        pdo_assignments: &[PdoAssignment],
+        watchdogs: &HashMap<u16, WatchdogBehavior>,
+    ) -> Result<(OffsetMap, HashMap<u16, String>), io::Error>;
+    /// Activates the master, which then brings the slaves to SAFEOP and OP.
+    fn activate(&mut self) -> Result<(), io::Error>;
+}
+
+/// Configures the master through its device, until it is turned into the bus
+/// of the cyclic task.
+pub(crate) struct MasterConfigurator {
+    master_id: u32,
+    configured: Option<(Master, DomainIdx)>,
+}
+
+impl MasterConfigurator {
+    pub(crate) fn new(master_id: u32) -> Self {
+        MasterConfigurator {
+            master_id,
+            configured: None,
+        }
+    }
+
+    pub(crate) fn into_bus(self) -> Result<MasterBus, io::Error> {
+        let (master, domain_idx) = self.configured.ok_or_else(not_configured)?;
+        Ok(MasterBus { master, domain_idx })
+    }
+
+    fn master(&mut self) -> Result<&mut Master, io::Error> {
+        self.configured
+            .as_mut()
+            .map(|(master, _)| master)
+            .ok_or_else(not_configured)
+    }
+}
+
+impl Configure for MasterConfigurator {
+    fn configure(
+        &mut self,
+        log_target: &str,
+        esi: &EtherCatInfo,
+        pdo_assignments: &[PdoAssignment],
+        watchdogs: &HashMap<u16, WatchdogBehavior>,
+    ) -> Result<(OffsetMap, HashMap<u16, String>), io::Error> {
+        let (master, domain_idx, offsets, slave_names) =
+            init_master_with_esi(log_target, esi, self.master_id, pdo_assignments, watchdogs)?;
+        self.configured = Some((master, domain_idx));
+        Ok((offsets, slave_names))
+    }
+
+    fn activate(&mut self) -> Result<(), io::Error> {
+        Ok(self.master()?.activate()?)
+    }
+}
+
+impl SdoAccess for MasterConfigurator {
+    fn upload(
+        &self,
+        slave_id: u16,
+        index: u16,
+        sub_index: u8,
+        len: usize,
+    ) -> Result<Vec<u8>, io::Error> {
+        let (master, _) = self.configured.as_ref().ok_or_else(not_configured)?;
+        master.upload(slave_id, index, sub_index, len)
+    }
+
+    fn download(
+        &mut self,
+        slave_id: u16,
+        index: u16,
+        sub_index: u8,
+        value: &[u8],
+    ) -> Result<(), io::Error> {
+        self.master()?.download(slave_id, index, sub_index, value)
+    }
+}
+
+fn not_configured() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "Master is not configured")
+}
+
+/// Sending side of the write queue. Bounded when the cyclic thread drains it,
+/// unbounded in manual cycle mode, where it is only drained by `step_cycle`
+/// and a bounded queue would block a caller queueing writes between steps.
+#[derive(Debug)]
+pub(crate) enum WriteSender {
+    Bounded(SyncSender<Vec<PdoWrite>>),
+    Unbounded(Sender<Vec<PdoWrite>>),
+}
+
+impl WriteSender {
+    pub(crate) fn channel(manual_cycle: bool) -> (Self, Receiver<Vec<PdoWrite>>) {
+        if manual_cycle {
+            let (tx, rx) = channel();
+            (WriteSender::Unbounded(tx), rx)
+        } else {
+            let (tx, rx) = sync_channel(5);
+            (WriteSender::Bounded(tx), rx)
+        }
+    }
+
+    pub(crate) fn send(&self, writes: Vec<PdoWrite>) -> Result<(), SendError<Vec<PdoWrite>>> {
+        match self {
+            WriteSender::Bounded(tx) => tx.send(writes),
+            WriteSender::Unbounded(tx) => tx.send(writes),
+        }
+    }
+}
+
+/// Everything a cycle needs, driven either by the cyclic thread or manually
+/// through `EtherCatController::step_cycle`.
+pub(crate) struct CycleTask {
+    pub(crate) log_target: String,
+    pub(crate) bus: Box<dyn Bus>,
+    pub(crate) rx: Receiver<Vec<PdoWrite>>,
+
+    pub(crate) data_publisher: Publisher,
+    pub(crate) output_publisher: Publisher,
+    pub(crate) ready_condvar: Arc<(Mutex<bool>, Condvar)>,
+    pub(crate) cycle_condvar: Arc<(Mutex<u64>, Condvar)>,
+    /// Last published cycle the consumer responded to, in synchronous
+    /// control mode.
+    pub(crate) response_condvar: Arc<(Mutex<u64>, Condvar)>,
+    pub(crate) response_timeout: Option<Duration>,
+
+    pub(crate) counters: Arc<MasterCounters>,
+    pub(crate) outputs_enabled: Arc<HashMap<u16, AtomicBool>>,
+    pub(crate) histogram: Arc<CycleHistogram>,
+    pub(crate) slave_states: Arc<RwLock<HashMap<u16, Option<AlState>>>>,
+    pub(crate) slave_positions: Vec<SlavePos>,
+    pub(crate) last_state_poll: Option<Instant>,
+
+    pub(crate) events: Arc<EventBus>,
+    pub(crate) data_logger: DataLogger,
+    pub(crate) shutdown_outputs: ShutdownBehavior,
+    pub(crate) shutdown_writes: Vec<PdoWrite>,
+    /// Applied once, in the first cycle.
+    pub(crate) initial_writes: Vec<PdoWrite>,
+    /// Slaves whose writes are only applied every n-th cycle.
+    pub(crate) update_divisors: HashMap<u16, u32>,
+    /// Writes waiting for a cycle where their slave is updated, with the
+    /// sequence number of their batch.
+    pub(crate) deferred_writes: Vec<(u64, PdoWrite)>,
+    /// Batches received from the queue so far.
+    pub(crate) received_batches: u64,
+
+    pub(crate) is_ready: bool,
+    pub(crate) ready_stable_cycles: u32,
+    /// Consecutive cycles the link was up while not ready yet.
+    pub(crate) ready_streak: u32,
+    pub(crate) cycle: u64,
+}
+
+impl fmt::Debug for CycleTask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CycleTask")
+            .field("is_ready", &self.is_ready)
+            .field("cycle", &self.cycle)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CycleTask {
+    /// Runs one receive/process/write/send cycle.
+    pub(crate) fn step(&mut self) -> Result<(), io::Error> {
+        let started = Instant::now();
+
+        receive(&self.log_target, self.bus.as_mut(), &self.counters);
+
+        let data = self.bus.data()?;
+
+        self.data_logger.log(self.cycle, data);
+
+        self.data_publisher.publish(data);
+
+        let published = {
+            let (lock, cvar) = &*self.cycle_condvar;
+            let mut published_cycles = lock.lock().unwrap_or_else(PoisonError::into_inner);
+            *published_cycles += 1;
+            cvar.notify_all();
+            *published_cycles
+        };
+
+        if let Some(timeout) = self.response_timeout {
+            let (lock, cvar) = &*self.response_condvar;
+            let responded = lock.lock().unwrap_or_else(PoisonError::into_inner);
+            let (_responded, result) = cvar
+                .wait_timeout_while(responded, timeout, |responded| *responded < published)
+                .unwrap_or_else(PoisonError::into_inner);
+            if result.timed_out() {
+                log::debug!(
+                    target: &self.log_target,
+                    "No response to cycle {} within {:?}",
+                    self.cycle,
+                    timeout
+                );
+            }
+        }
+
+        for write in self.initial_writes.drain(..) {
+            if !write.apply(data) {
+                log::error!(
+                    target: &self.log_target,
+                    "Initial value at {:?} does not fit",
+                    write.range
+                );
+            }
+        }
+
+        let writes = due_writes(
+            &self.rx,
+            self.cycle,
+            &self.update_divisors,
+            &mut self.deferred_writes,
+            &mut self.received_batches,
+        );
+        apply_writes(
+            &self.log_target,
+            writes,
+            data,
+            &self.outputs_enabled,
+            &self.counters,
+            &self.events,
+        );
+        self.counters.write_batches_applied.store(
+            applied_batches(self.received_batches, &self.deferred_writes),
+            Ordering::Relaxed,
+        );
+
+        self.output_publisher.publish(data);
+
+        send(&self.log_target, self.bus.as_mut(), &self.counters);
+
+        if !self.is_ready {
+            let link_up = self.bus.link_up()?;
+            log::debug!(target: &self.log_target, "Link up: {}", link_up);
+
+            if link_up {
+                self.ready_streak += 1;
+            } else {
+                self.ready_streak = 0;
+            }
+
+            if self.ready_streak >= self.ready_stable_cycles {
+                let (lock, cvar) = &*self.ready_condvar;
+                let mut ready = lock.lock().unwrap_or_else(PoisonError::into_inner);
+                *ready = true;
+                cvar.notify_all();
+                self.is_ready = true;
+
+                log::info!(target: &self.log_target, "Master ready!");
+            }
+        }
+
+        if self
+            .last_state_poll
+            .is_none_or(|t| t.elapsed() >= SLAVE_STATE_POLL_PERIOD)
+        {
+            poll_slave_states(
+                &self.log_target,
+                self.bus.as_ref(),
+                &self.slave_positions,
+                &self.slave_states,
+            );
+            self.last_state_poll = Some(Instant::now());
+        }
+
+        self.cycle += 1;
+        self.histogram.record(started.elapsed());
+
+        Ok(())
+    }
+
+    /// Reports that the last cycle took `overrun` longer than the period.
+    pub(crate) fn overrun(&self, overrun: Duration) {
+        // `step` already counted the cycle that overran.
+        let cycle = self.cycle.saturating_sub(1);
+        log::warn!(target: &self.log_target, "Cycle {} overran by {:?}", cycle, overrun);
+        self.counters.count_overrun(overrun);
+        self.events.emit(BusEvent::CycleOverrun { cycle, overrun });
+    }
+
+    /// Final cycle applying the shutdown outputs before bringing the bus down.
+    pub(crate) fn shutdown(mut self) {
+        receive(&self.log_target, self.bus.as_mut(), &self.counters);
+
+        match self.bus.data() {
+            Ok(data) => {
+                let mut writes: Vec<PdoWrite> = self
+                    .deferred_writes
+                    .drain(..)
+                    .map(|(_, write)| write)
+                    .collect();
+                while let Ok(batch) = self.rx.try_recv() {
+                    self.received_batches += 1;
+                    writes.extend(batch);
+                }
+                apply_writes(
+                    &self.log_target,
+                    writes,
+                    data,
+                    &self.outputs_enabled,
+                    &self.counters,
+                    &self.events,
+                );
+                self.counters
+                    .write_batches_applied
+                    .store(self.received_batches, Ordering::Relaxed);
+
+                match self.shutdown_outputs {
+                    ShutdownBehavior::Zero => data.fill(0),
+                    ShutdownBehavior::Hold => {}
+                    ShutdownBehavior::SafeValue(_) => {
+                        for write in &self.shutdown_writes {
+                            if !write.apply(data) {
+                                log::error!(
+                                    target: &self.log_target,
+                                    "Shutdown value for {:?} does not fit in the domain",
+                                    write.range
+                                );
+                            }
+                        }
+                    }
+                }
+
+                send(&self.log_target, self.bus.as_mut(), &self.counters);
+            }
+            Err(e) => {
+                log::error!(target: &self.log_target, "Unable to apply the shutdown outputs: {}", e)
+            }
+        }
+
+        if let Err(e) = self.bus.deactivate() {
+            log::error!(target: &self.log_target, "Failed to deactivate master: {}", e);
+        }
+        self.counters.reserved.store(false, Ordering::Relaxed);
+        log::info!(target: &self.log_target, "Master stopped!");
+    }
+}
+
+fn receive(log_target: &str, bus: &mut dyn Bus, counters: &MasterCounters) {
+    match bus.receive() {
+        Ok(()) => count(&counters.frames_received),
+        Err(e) => {
+            count(&counters.receive_errors);
+            log::warn!(target: log_target, "Failed to receive frames: {}", e);
+        }
+    }
+    if let Err(e) = bus.process() {
+        count(&counters.process_errors);
+        log::warn!(target: log_target, "Failed to process domain: {}", e);
+    }
+    match bus.working_counter() {
+        Ok(working_counter) => {
+            counters
+                .domain_working_counter
+                .store(working_counter, Ordering::Relaxed);
+            counters.domain_state_known.store(true, Ordering::Relaxed);
+        }
+        Err(e) => log::debug!(target: log_target, "Failed to read domain state: {}", e),
+    }
+}
+
+fn send(log_target: &str, bus: &mut dyn Bus, counters: &MasterCounters) {
+    match bus.send() {
+        Ok(()) => count(&counters.frames_sent),
+        Err(e) => {
+            count(&counters.send_errors);
+            log::warn!(target: log_target, "Failed to send frames: {}", e);
+        }
+    }
+}
+
+fn poll_slave_states(
+    log_target: &str,
+    bus: &dyn Bus,
+    slave_positions: &[SlavePos],
+    slave_states: &RwLock<HashMap<u16, Option<AlState>>>,
+) {
+    let states: HashMap<u16, Option<AlState>> = slave_positions
+        .iter()
+        .map(|slave_pos| (u16::from(*slave_pos), bus.slave_state(*slave_pos)))
+        .collect();
+
+    let mut slave_states = slave_states.write().unwrap_or_else(PoisonError::into_inner);
+
+    for (slave_id, state) in &states {
+        let was_present = slave_states.get(slave_id).is_some_and(Option::is_some);
+        match (was_present, state.is_some()) {
+            (true, false) => {
+                log::warn!(target: log_target, "Slave {} is not responding anymore", slave_id)
+            }
+            (false, true) => log::info!(target: log_target, "Slave {} is present", slave_id),
+            _ => {}
+        }
+    }
+
+    *slave_states = states;
+}
+
+/// Pending writes to apply in this cycle, deferring the ones of the slaves
+/// not updated in this cycle.
+fn due_writes(
+    rx: &Receiver<Vec<PdoWrite>>,
+    cycle: u64,
+    update_divisors: &HashMap<u16, u32>,
+    deferred_writes: &mut Vec<(u64, PdoWrite)>,
+    received_batches: &mut u64,
+) -> Vec<PdoWrite> {
+    let is_due = |write: &PdoWrite| {
+        update_divisors
+            .get(&write.slave_id)
+            .is_none_or(|divisor| cycle.is_multiple_of(u64::from(*divisor)))
+    };
+
+    let mut writes: Vec<PdoWrite> = deferred_writes
+        .extract_if(.., |(_, write)| is_due(write))
+        .map(|(_, write)| write)
+        .collect();
+
+    while let Ok(batch) = rx.try_recv() {
+        let sequence = *received_batches;
+        *received_batches += 1;
+
+        if update_divisors.is_empty() {
+            writes.extend(batch);
+            continue;
+        }
+        for write in batch {
+            if is_due(&write) {
+                writes.push(write);
+            } else {
+                deferred_writes.push((sequence, write));
+            }
+        }
+    }
+
+    writes
+}
+
+/// Number of batches whose writes were all applied: the ones received before
+/// the oldest batch with deferred writes. Batches are applied in order, so
+/// this is what `flush_writes` waits on.
+fn applied_batches(received_batches: u64, deferred_writes: &[(u64, PdoWrite)]) -> u64 {
+    deferred_writes
+        .iter()
+        .map(|(sequence, _)| *sequence)
+        .min()
+        .unwrap_or(received_batches)
+}
+
+fn apply_writes(
+    log_target: &str,
+    writes: Vec<PdoWrite>,
+    data: &mut [u8],
+    outputs_enabled: &HashMap<u16, AtomicBool>,
+    counters: &MasterCounters,
+    events: &EventBus,
+) {
+    for write in writes {
+        if outputs_enabled
+            .get(&write.slave_id)
+            .is_some_and(|flag| flag.load(Ordering::Relaxed))
+        {
+            if !write.apply(data) {
+                log::error!(
+                    target: log_target,
+                    "Skipping write of {} bytes at {:?} for slave {}, domain is {} bytes",
+                    write.value.len(),
+                    write.range,
+                    write.slave_id,
+                    data.len()
+                );
+                count(&counters.invalid_writes);
+                events.emit(BusEvent::InvalidWrite {
+                    slave_id: write.slave_id,
+                    range: write.range.clone(),
+                    value_len: write.value.len(),
+                    domain_len: data.len(),
+                });
+            }
+        } else {
+            log::debug!(
+                target: log_target,
+                "Discarding write to disabled slave {}",
+                write.slave_id
+            );
+        }
+    }
+}
+
+fn count(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{sync::atomic::AtomicU32, thread};
+
+    use super::*;
+
+    type SimulatedSlaves = Box<dyn FnMut(&mut [u8]) + Send>;
+
+    /// Bus looping the outputs back, keeping the images it sent.
+    pub(crate) struct MockBus {
+        pub(crate) image: Vec<u8>,
+        pub(crate) sent: Arc<Mutex<Vec<Vec<u8>>>>,
+        pub(crate) link_up: Arc<AtomicBool>,
+        /// Entries registered when configuring the slaves.
+        pub(crate) offsets: OffsetMap,
+        pub(crate) activated: Arc<AtomicBool>,
+        pub(crate) deactivated: Arc<AtomicBool>,
+        pub(crate) receive_fails: Arc<AtomicBool>,
+        /// AL state of the slaves, OP for the ones not listed.
+        pub(crate) slave_states: Arc<Mutex<HashMap<u16, Option<AlState>>>>,
+        /// Working counter the domain reports, a full output and input
+        /// exchange with a single slave by default.
+        pub(crate) working_counter: Arc<AtomicU32>,
+        pub(crate) panic_after_sends: Option<usize>,
+        /// Time each exchange takes.
+        pub(crate) delay: Duration,
+        /// Simulated slaves, updating the image before it is received.
+        pub(crate) slaves: Option<SimulatedSlaves>,
+    }
+
+    impl MockBus {
+        pub(crate) fn new(len: usize) -> Self {
+            MockBus {
+                image: vec![0; len],
+                sent: Arc::default(),
+                link_up: Arc::new(AtomicBool::new(true)),
+                offsets: OffsetMap::new(),
+                activated: Arc::default(),
+                deactivated: Arc::default(),
+                receive_fails: Arc::default(),
+                slave_states: Arc::default(),
+                working_counter: Arc::new(AtomicU32::new(3)),
+                panic_after_sends: None,
+                delay: Duration::ZERO,
+                slaves: None,
+            }
+        }
+    }
+
+    impl Bus for MockBus {
+        fn receive(&mut self) -> Result<(), io::Error> {
+            if self.receive_fails.load(Ordering::Relaxed) {
+                return Err(io::Error::other("No frame received"));
+            }
+            if let Some(slaves) = &mut self.slaves {
+                slaves(&mut self.image);
+            }
+            Ok(())
+        }
+
+        fn process(&mut self) -> Result<(), io::Error> {
+            Ok(())
+        }
+
+        fn working_counter(&mut self) -> Result<u32, io::Error> {
+            Ok(self.working_counter.load(Ordering::Relaxed))
+        }
+
+        fn data(&mut self) -> Result<&mut [u8], io::Error> {
+            Ok(&mut self.image)
+        }
+
+        fn send(&mut self) -> Result<(), io::Error> {
+            thread::sleep(self.delay);
+            let mut sent = self.sent.lock().unwrap();
+            if self.panic_after_sends == Some(sent.len()) {
+                drop(sent);
+                panic!("Bus failure");
+            }
+            sent.push(self.image.clone());
+            Ok(())
+        }
+
+        fn link_up(&mut self) -> Result<bool, io::Error> {
+            Ok(self.link_up.load(Ordering::Relaxed))
+        }
+
+        fn slave_state(&self, slave_pos: SlavePos) -> Option<AlState> {
+            self.slave_states
+                .lock()
+                .unwrap()
+                .get(&u16::from(slave_pos))
+                .copied()
+                .unwrap_or(Some(AlState::Op))
+        }
+
+        fn deactivate(&mut self) -> Result<(), io::Error> {
+            self.deactivated.store(true, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    impl Configure for MockBus {
+        fn configure(
+            &mut self,
+            _log_target: &str,
+            _esi: &EtherCatInfo,
+            _pdo_assignments: &[PdoAssignment],
+            _watchdogs: &HashMap<u16, WatchdogBehavior>,
+        ) -> Result<(OffsetMap, HashMap<u16, String>), io::Error> {
+            Ok((self.offsets.clone(), HashMap::new()))
+        }
+
+        fn activate(&mut self) -> Result<(), io::Error> {
+            self.activated.store(true, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn manual_write_queue_does_not_block() {
+        let (tx, rx) = WriteSender::channel(true);
+
+        for slave_id in 0..20 {
+            tx.send(vec![PdoWrite::new(slave_id, 0..1, vec![1])])
+                .unwrap();
+        }
+
+        assert_eq!(rx.try_iter().count(), 20);
+    }
+
+    #[test]
+    fn cyclic_write_queue_is_bounded() {
+        let (tx, _rx) = WriteSender::channel(false);
+
+        let WriteSender::Bounded(tx) = tx else {
+            panic!("Expected a bounded queue");
+        };
+        for _ in 0..5 {
+            tx.try_send(Vec::new()).unwrap();
+        }
+        assert!(tx.try_send(Vec::new()).is_err());
+    }
+}
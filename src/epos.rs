@@ -0,0 +1,544 @@
+use std::{f64::consts::PI, io, time::Duration};
+
+use crate::{config::EposKind, EtherCatController, TypedWrite};
+
+const CONTROLWORD: &str = "Controlword";
+const STATUSWORD: &str = "Statusword";
+const VELOCITY_ACTUAL_VALUE: &str = "Velocity actual value";
+const TORQUE_ACTUAL_VALUE: &str = "Torque actual value";
+const TARGET_POSITION: &str = "Target position";
+
+const ERROR_CODE_INDEX: u16 = 0x603F;
+
+const CONTROLWORD_NEW_SETPOINT: u16 = 1 << 4;
+const CONTROLWORD_CHANGE_SET_IMMEDIATELY: u16 = 1 << 5;
+const CONTROLWORD_RELATIVE: u16 = 1 << 6;
+const CONTROLWORD_FAULT_RESET: u16 = 1 << 7;
+const STATUSWORD_FAULT: u16 = 1 << 3;
+const STATUSWORD_TARGET_REACHED: u16 = 1 << 10;
+const STATUSWORD_SETPOINT_ACKNOWLEDGE: u16 = 1 << 12;
+
+const SETPOINT_ACKNOWLEDGE_TIMEOUT: Duration = Duration::from_secs(1);
+const CYCLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FaultInfo {
+    pub error_code: u16,
+    pub description: &'static str,
+}
+
+impl FaultInfo {
+    pub fn from_error_code(error_code: u16) -> Self {
+        FaultInfo {
+            error_code,
+            description: describe_error_code(error_code),
+        }
+    }
+}
+
+/// Describes an EPOS error code (object 0x603F), falling back on the
+/// CiA 301 error class when the code is not a known EPOS one.
+fn describe_error_code(error_code: u16) -> &'static str {
+    match error_code {
+        0x0000 => "No error",
+        0x1000 => "Generic error",
+        0x1090 => "Firmware incompatibility error",
+        0x2310 => "Overcurrent error",
+        0x2320 => "Power stage protection error",
+        0x3210 => "Overvoltage error",
+        0x3220 => "Undervoltage error",
+        0x4210 => "Thermal overload error",
+        0x4380 => "Thermal motor overload error",
+        0x5113 => "Logic supply voltage too low error",
+        0x5280 => "Supply voltage output stage too low error",
+        0x6320 => "Software parameter error",
+        0x7320 => "Position sensor error",
+        0x7380 => "Position sensor breach error",
+        0x7381 => "Position sensor resolution error",
+        0x7382 => "Position sensor index error",
+        0x7388 => "Hall sensor error",
+        0x7389 => "Hall sensor not found error",
+        0x738A => "Hall angle detection error",
+        0x8611 => "Following error",
+        _ => match error_code >> 12 {
+            0x1 => "Generic error",
+            0x2 => "Current error",
+            0x3 => "Voltage error",
+            0x4 => "Temperature error",
+            0x5 => "Device hardware error",
+            0x6 => "Device software error",
+            0x7 => "Additional modules error",
+            0x8 => "Monitoring error",
+            0x9 => "External error",
+            0xF => "Device specific error",
+            _ => "Unknown error",
+        },
+    }
+}
+
+/// Decodes the fault of a drive from its statusword, reading its error code
+/// only if the fault bit is set.
+fn decode_fault<F>(statusword: u16, read_error_code: F) -> Result<Option<FaultInfo>, io::Error>
+where
+    F: FnOnce() -> Result<Vec<u8>, io::Error>,
+{
+    if statusword & STATUSWORD_FAULT == 0 {
+        return Ok(None);
+    }
+
+    let raw = read_error_code()?;
+    let error_code =
+        u16::from_le_bytes(raw.try_into().map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "Invalid error code length")
+        })?);
+
+    Ok(Some(FaultInfo::from_error_code(error_code)))
+}
+
+impl EposKind {
+    /// Converts a raw velocity (motor side, in rpm, the default velocity unit
+    /// of object 0x60A9) into an output velocity in rad/s.
+    pub fn velocity_from_raw(&self, raw: i32) -> f64 {
+        raw as f64 * 2.0 * PI / 60.0 / self.reduction as f64
+    }
+
+    /// Converts a raw torque (per thousand of the motor rated torque) into an
+    /// output torque in N.m.
+    pub fn torque_from_raw(&self, raw: i16) -> f64 {
+        raw as f64 / 1000.0 * self.motor_rated_torque() * self.reduction as f64
+    }
+
+    /// Motor rated torque in N.m, from the torque constant and the rated
+    /// current unless given directly.
+    pub fn motor_rated_torque(&self) -> f64 {
+        if self.rated_torque > 0.0 {
+            self.rated_torque as f64
+        } else {
+            self.torque_constant as f64 * self.rated_current as f64
+        }
+    }
+}
+
+impl EtherCatController {
+    pub fn get_actual_velocity(&self, slave_id: u16) -> Option<f64> {
+        let epos = self.epos_config(slave_id)?;
+        let raw = self.get_pdo_register(slave_id, VELOCITY_ACTUAL_VALUE)?;
+
+        Some(epos.velocity_from_raw(i32::from_le_bytes(raw.try_into().ok()?)))
+    }
+
+    pub fn get_actual_torque(&self, slave_id: u16) -> Option<f64> {
+        let epos = self.epos_config(slave_id)?;
+        let raw = self.get_pdo_register(slave_id, TORQUE_ACTUAL_VALUE)?;
+
+        Some(epos.torque_from_raw(i16::from_le_bytes(raw.try_into().ok()?)))
+    }
+
+    pub fn get_statusword(&self, slave_id: u16) -> Option<u16> {
+        self.read_statusword(slave_id).ok()
+    }
+
+    /// Returns the decoded error of a faulted drive, or `None` if the
+    /// statusword fault bit is not set. Fails if the statusword is not mapped
+    /// or was not received yet.
+    pub fn fault_info(&self, slave_id: u16) -> Result<Option<FaultInfo>, io::Error> {
+        let statusword = self.read_statusword(slave_id)?;
+
+        decode_fault(statusword, || {
+            self.sdo_read(slave_id, ERROR_CODE_INDEX, 0, 2)
+        })
+    }
+
+    /// Whether the drive reports its target as reached (statusword bit 10).
+    pub fn is_target_reached(&self, slave_id: u16) -> Option<bool> {
+        self.get_statusword(slave_id)
+            .map(|statusword| statusword & STATUSWORD_TARGET_REACHED != 0)
+    }
+
+    /// Starts an absolute move in profile position mode: sets the target,
+    /// raises the new set-point bit, waits for the drive to acknowledge it
+    /// then clears the bit. With `immediate` the move replaces the one in
+    /// progress instead of being queued after it. The drive must already be
+    /// in operation enabled, in profile position mode.
+    ///
+    /// It waits for cycles between the steps: in manual cycle mode another
+    /// thread must step them, or it fails with `TimedOut`.
+    pub fn move_to_position(
+        &self,
+        slave_id: u16,
+        target_ticks: i32,
+        immediate: bool,
+    ) -> Result<(), io::Error> {
+        for register in [CONTROLWORD, TARGET_POSITION, STATUSWORD] {
+            self.check_mapped(slave_id, register)?;
+        }
+        let controlword = self
+            .get_output_pdo_register(slave_id, CONTROLWORD)
+            .and_then(|raw| raw.try_into().ok())
+            .map(u16::from_le_bytes)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::WouldBlock, "No data sent yet"))?;
+
+        let mut controlword = controlword
+            & !(CONTROLWORD_NEW_SETPOINT
+                | CONTROLWORD_CHANGE_SET_IMMEDIATELY
+                | CONTROLWORD_RELATIVE);
+        if immediate {
+            controlword |= CONTROLWORD_CHANGE_SET_IMMEDIATELY;
+        }
+
+        self.write_many(&[
+            TypedWrite::I32 {
+                slave: slave_id,
+                register: TARGET_POSITION.to_string(),
+                index: 0,
+                value: target_ticks,
+            },
+            TypedWrite::U16 {
+                slave: slave_id,
+                register: CONTROLWORD.to_string(),
+                index: 0,
+                value: controlword,
+            },
+        ])?;
+        self.wait_cycles(2)?;
+
+        self.write_controlword(slave_id, controlword | CONTROLWORD_NEW_SETPOINT)?;
+        let acknowledged = self.wait_until(
+            slave_id,
+            STATUSWORD,
+            0,
+            |raw| {
+                raw.try_into()
+                    .map(u16::from_le_bytes)
+                    .is_ok_and(|statusword| statusword & STATUSWORD_SETPOINT_ACKNOWLEDGE != 0)
+            },
+            SETPOINT_ACKNOWLEDGE_TIMEOUT,
+        );
+        self.write_controlword(slave_id, controlword)?;
+
+        acknowledged
+    }
+
+    /// Clears a drive fault by sending a rising edge on the controlword
+    /// fault reset bit. Like `move_to_position`, it needs the cycles to run.
+    pub fn fault_reset(&self, slave_id: u16) -> Result<(), io::Error> {
+        self.write_controlword(slave_id, 0)?;
+        self.write_controlword(slave_id, CONTROLWORD_FAULT_RESET)?;
+        self.write_controlword(slave_id, 0)
+    }
+
+    fn read_statusword(&self, slave_id: u16) -> Result<u16, io::Error> {
+        self.check_mapped(slave_id, STATUSWORD)?;
+        let raw = self
+            .get_pdo_register(slave_id, STATUSWORD)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::WouldBlock, "No data received yet"))?;
+
+        raw.try_into()
+            .map(u16::from_le_bytes)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid statusword length"))
+    }
+
+    fn check_mapped(&self, slave_id: u16, register: &str) -> Result<(), io::Error> {
+        if self.offset_map().entry(slave_id, register, 0).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} of slave {} is not mapped", register, slave_id),
+            ));
+        }
+        Ok(())
+    }
+
+    // Waits two cycles so the value is on the wire for at least a full cycle.
+    fn write_controlword(&self, slave_id: u16, controlword: u16) -> Result<(), io::Error> {
+        self.set_pdo_register(slave_id, CONTROLWORD, controlword.to_le_bytes().to_vec())?;
+        self.wait_cycles(2)
+    }
+
+    // Fails rather than blocking forever when no cycle runs, e.g. in manual
+    // cycle mode with nobody stepping them.
+    fn wait_cycles(&self, cycles: usize) -> Result<(), io::Error> {
+        for _ in 0..cycles {
+            if !self.wait_for_next_cycle_timeout(CYCLE_TIMEOUT) {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Timed out waiting for the next cycle",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    use super::*;
+    use crate::{
+        config::SlaveConfig,
+        cycle::tests::MockBus,
+        ethercat_controller::tests::{builder, mock_controller},
+        offsets::{tests::layout, Direction},
+        OffsetMap,
+    };
+
+    type Received = Arc<Mutex<Vec<(u16, i32)>>>;
+
+    /// Controller of a drive in profile position mode, acknowledging the new
+    /// set-points if `acknowledges`, with the `(controlword, target)` pairs
+    /// the drive received, each change once.
+    fn ppm_drive(acknowledges: bool) -> (EtherCatController, Received) {
+        let mut offsets = OffsetMap::new();
+        offsets.insert(
+            0,
+            CONTROLWORD.to_string(),
+            layout(0x6040, 16, 0, Direction::Output),
+        );
+        offsets.insert(
+            0,
+            TARGET_POSITION.to_string(),
+            layout(0x607A, 32, 2, Direction::Output),
+        );
+        offsets.insert(
+            0,
+            STATUSWORD.to_string(),
+            layout(0x6041, 16, 6, Direction::Input),
+        );
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let history = Arc::clone(&received);
+        let mut bus = MockBus::new(8);
+        bus.slaves = Some(Box::new(move |image: &mut [u8]| {
+            let controlword = u16::from_le_bytes([image[0], image[1]]);
+            let target = i32::from_le_bytes([image[2], image[3], image[4], image[5]]);
+            let mut history = history.lock().unwrap();
+            if history.last() != Some(&(controlword, target)) {
+                history.push((controlword, target));
+            }
+
+            let new_setpoint = controlword & CONTROLWORD_NEW_SETPOINT != 0;
+            let mut statusword = 0;
+            if acknowledges && new_setpoint {
+                statusword |= STATUSWORD_SETPOINT_ACKNOWLEDGE;
+            }
+            if target != 0 && !new_setpoint {
+                statusword |= STATUSWORD_TARGET_REACHED;
+            }
+            image[6..8].copy_from_slice(&statusword.to_le_bytes());
+        }));
+
+        let controller = mock_controller(builder().manual_cycle(true), bus, offsets);
+        controller
+            .set_pdo_register(0, CONTROLWORD, vec![0x0F, 0])
+            .unwrap();
+        controller.step_cycle().unwrap();
+        controller.step_cycle().unwrap();
+
+        (controller, received)
+    }
+
+    /// Runs `call` while stepping the cycles it waits for.
+    fn stepping<T: Send>(controller: &EtherCatController, call: impl FnOnce() -> T + Send) -> T {
+        thread::scope(|scope| {
+            let call = scope.spawn(call);
+            while !call.is_finished() {
+                controller.step_cycle().unwrap();
+                thread::sleep(Duration::from_millis(1));
+            }
+            call.join().unwrap()
+        })
+    }
+
+    fn epos() -> EposKind {
+        EposKind {
+            id: 0,
+            encoder_resolution: 4096,
+            reduction: 10.0,
+            rated_torque: 0.0,
+            torque_constant: 0.05,
+            rated_current: 2.0,
+        }
+    }
+
+    #[test]
+    fn known_error_codes_are_described() {
+        assert_eq!(describe_error_code(0x0000), "No error");
+        assert_eq!(describe_error_code(0x2310), "Overcurrent error");
+        assert_eq!(describe_error_code(0x8611), "Following error");
+    }
+
+    #[test]
+    fn unknown_error_codes_fall_back_on_their_class() {
+        assert_eq!(describe_error_code(0x3299), "Voltage error");
+        assert_eq!(describe_error_code(0xFF01), "Device specific error");
+        assert_eq!(describe_error_code(0xA000), "Unknown error");
+    }
+
+    #[test]
+    fn fault_is_decoded_from_the_statusword_and_error_code() {
+        // Operation enabled, no fault: the error code is not read.
+        let fault = decode_fault(0x0237, || panic!("Error code read")).unwrap();
+        assert_eq!(fault, None);
+
+        // Fault bit set.
+        let fault = decode_fault(0x0218, || Ok(vec![0x11, 0x86])).unwrap();
+        assert_eq!(
+            fault,
+            Some(FaultInfo {
+                error_code: 0x8611,
+                description: "Following error",
+            })
+        );
+
+        let err = decode_fault(0x0008, || Ok(vec![0x11])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn velocity_is_converted_from_motor_rpm() {
+        let epos = epos();
+
+        assert_eq!(epos.velocity_from_raw(0), 0.0);
+        // 600 rpm at the motor is 60 rpm, i.e. one turn per second, at the
+        // output.
+        assert!((epos.velocity_from_raw(600) - 2.0 * PI).abs() < 1e-9);
+        assert!((epos.velocity_from_raw(-600) + 2.0 * PI).abs() < 1e-9);
+    }
+
+    #[test]
+    fn torque_is_converted_from_per_thousand_of_rated_torque() {
+        let mut epos = epos();
+
+        // Rated torque of 0.05 N.m/A * 2 A = 0.1 N.m at the motor.
+        assert!((epos.motor_rated_torque() - 0.1).abs() < 1e-6);
+        assert!((epos.torque_from_raw(1000) - 1.0).abs() < 1e-6);
+        assert!((epos.torque_from_raw(-500) + 0.5).abs() < 1e-6);
+
+        epos.rated_torque = 0.2;
+        assert!((epos.torque_from_raw(1000) - 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn unmapped_actual_values_are_none() {
+        let mut offsets = OffsetMap::new();
+        offsets.insert(
+            0,
+            VELOCITY_ACTUAL_VALUE.to_string(),
+            layout(0x606C, 32, 0, Direction::Input),
+        );
+        let mut bus = MockBus::new(4);
+        bus.image = 600i32.to_le_bytes().to_vec();
+        let mut builder = builder().manual_cycle(true);
+        builder.slaves.insert(0, SlaveConfig::Epos(epos()));
+        let controller = mock_controller(builder, bus, offsets);
+        controller.step_cycle().unwrap();
+
+        let velocity = controller.get_actual_velocity(0).unwrap();
+        assert!((velocity - 2.0 * PI).abs() < 1e-9);
+        // The torque is not mapped in the PDOs.
+        assert_eq!(controller.get_actual_torque(0), None);
+    }
+
+    #[test]
+    fn fault_info_fails_without_a_statusword() {
+        let controller = mock_controller(
+            builder().manual_cycle(true),
+            MockBus::new(2),
+            OffsetMap::new(),
+        );
+        let err = controller.fault_info(0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(controller.get_statusword(0), None);
+
+        let mut offsets = OffsetMap::new();
+        offsets.insert(
+            0,
+            STATUSWORD.to_string(),
+            layout(0x6041, 16, 0, Direction::Input),
+        );
+        let controller = mock_controller(builder().manual_cycle(true), MockBus::new(2), offsets);
+        let err = controller.fault_info(0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        controller.step_cycle().unwrap();
+        assert_eq!(controller.get_statusword(0), Some(0));
+        assert_eq!(controller.fault_info(0).unwrap(), None);
+    }
+
+    #[test]
+    fn move_to_position_fails_if_a_register_is_not_mapped() {
+        let mut offsets = OffsetMap::new();
+        offsets.insert(
+            0,
+            CONTROLWORD.to_string(),
+            layout(0x6040, 16, 0, Direction::Output),
+        );
+        offsets.insert(
+            0,
+            TARGET_POSITION.to_string(),
+            layout(0x607A, 32, 2, Direction::Output),
+        );
+        let bus = MockBus::new(6);
+        let sent = Arc::clone(&bus.sent);
+        let controller = mock_controller(builder().manual_cycle(true), bus, offsets);
+        controller.step_cycle().unwrap();
+
+        let err = controller.move_to_position(0, 1000, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(controller.is_target_reached(0), None);
+
+        // Nothing was written.
+        controller.step_cycle().unwrap();
+        assert_eq!(sent.lock().unwrap()[1], [0; 6]);
+    }
+
+    #[test]
+    fn fault_reset_times_out_without_cycles() {
+        let mut offsets = OffsetMap::new();
+        offsets.insert(
+            0,
+            CONTROLWORD.to_string(),
+            layout(0x6040, 16, 0, Direction::Output),
+        );
+        let controller = mock_controller(builder().manual_cycle(true), MockBus::new(2), offsets);
+
+        let err = controller.fault_reset(0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn move_to_position_runs_the_setpoint_handshake() {
+        let (controller, received) = ppm_drive(true);
+        assert_eq!(controller.is_target_reached(0), Some(false));
+
+        stepping(&controller, || controller.move_to_position(0, 1000, true)).unwrap();
+        controller.step_cycle().unwrap();
+
+        // Target first, then a rising then falling edge of the new set-point
+        // bit, with change set immediately kept.
+        assert_eq!(
+            *received.lock().unwrap(),
+            [(0, 0), (0x0F, 0), (0x2F, 1000), (0x3F, 1000), (0x2F, 1000)]
+        );
+        assert_eq!(controller.is_target_reached(0), Some(true));
+    }
+
+    #[test]
+    fn unacknowledged_setpoint_times_out() {
+        let (controller, received) = ppm_drive(false);
+
+        let err =
+            stepping(&controller, || controller.move_to_position(0, 1000, false)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        controller.step_cycle().unwrap();
+
+        // The new set-point bit is cleared anyway.
+        assert_eq!(
+            *received.lock().unwrap(),
+            [(0, 0), (0x0F, 0), (0x0F, 1000), (0x1F, 1000), (0x0F, 1000)]
+        );
+    }
+}
@@ -1,287 +1,3383 @@
 use std::{
     collections::HashMap,
+    fmt,
     fs::File,
     io::{self, Read},
     ops::Range,
+    panic::{self, AssertUnwindSafe},
     sync::{
-        mpsc::{sync_channel, SyncSender},
-        Arc, Condvar, Mutex, RwLock,
+        atomic::{AtomicBool, Ordering},
+        mpsc::Receiver,
+        Arc, Condvar, Mutex, PoisonError, RwLock,
     },
-    thread,
-    time::Duration,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
 use ethercat::{
-    DomainIdx, Master, Offset, PdoCfg, PdoEntryIdx, PdoEntryInfo, PdoEntryPos, SlaveAddr, SlaveId,
-    SlavePos, SmCfg,
+    AlState, DomainIdx, Master, MasterAccess, PdoCfg, PdoEntryIdx, PdoEntryInfo, PdoEntryPos,
+    SlaveAddr, SlaveId, SlavePos, SmCfg, WatchdogMode,
 };
-use ethercat_esi::EtherCatInfo;
+use ethercat_esi::{Device, EtherCatInfo, Pdo};
 
+use crate::{
+    builder::EsiSource,
+    config::{
+        Config, EposKind, EthercatConfig, PdoAssignment, RegisterType, SlaveConfig,
+        WatchdogBehavior,
+    },
+    cycle::{Bus, Configure, CycleTask, MasterConfigurator, PdoWrite, WriteSender},
+    events::{BusEvent, EventBus},
+    logging::{log_target, DataLogger},
+    metrics::{CycleHistogram, MasterCounters, MasterMetrics, MetricsSnapshot, TimingStats},
+    offsets::{Direction, EntryLayout, LayoutChange, OffsetMap},
+    plan::{ConfigurationPlan, PlannedSyncManager},
+    sdo::{bring_up, sdo_download, sdo_upload},
+    snapshot::{Publisher, Snapshot},
+    values::DataType,
+    EtherCatControllerBuilder, ShutdownBehavior,
+};
+
+/// Controller of one EtherCAT master.
+///
+/// All the state is owned by the instance and the master is opened through
+/// its own device (`/dev/EtherCAT<master_id>`), so several controllers can
+/// run side by side in the same process as long as they use different
+/// masters. Each one runs its cyclic task in a thread named after its master.
 #[derive(Debug)]
 pub struct EtherCatController {
-    offsets: HashMap<SlavePos, HashMap<String, (PdoEntryIdx, u8, Offset)>>,
+    master_id: u32,
+    log_target: String,
+    cycle_period: Duration,
+    offsets: OffsetMap,
+    domain_size: usize,
 
-    data_lock: Arc<RwLock<Option<Vec<u8>>>>,
+    pub(crate) data_snapshot: Arc<Snapshot>,
+    output_snapshot: Arc<Snapshot>,
     ready_condvar: Arc<(Mutex<bool>, Condvar)>,
-    cycle_condvar: Arc<(Mutex<bool>, Condvar)>,
+    cycle_condvar: Arc<(Mutex<u64>, Condvar)>,
+    response_condvar: Arc<(Mutex<u64>, Condvar)>,
+
+    cmd_buff: WriteSender,
+    outputs_enabled: Arc<HashMap<u16, AtomicBool>>,
 
-    cmd_buff: SyncSender<(Range<usize>, Vec<u8>)>,
+    master_counters: Arc<MasterCounters>,
+    histogram: Arc<CycleHistogram>,
+    events: Arc<EventBus>,
+    slave_states: Arc<RwLock<HashMap<u16, Option<AlState>>>>,
+
+    stop_flag: Arc<AtomicBool>,
+    cycle_thread: Option<JoinHandle<()>>,
+    manual_task: Option<Mutex<CycleTask>>,
+    last_error: Arc<Mutex<Option<String>>>,
+
+    slaves: HashMap<u16, SlaveConfig>,
+    lenient_writes: bool,
+    allow_input_writes: bool,
+    settings: EtherCatControllerBuilder,
+    slave_names: HashMap<u16, String>,
 }
 
 impl EtherCatController {
-    pub fn open(
-        filename: &String,
-        master_id: u32,
+    pub fn open(filename: &str, master_id: u32, cycle_period: Duration) -> Result<Self, io::Error> {
+        EtherCatControllerBuilder::new(filename, master_id, cycle_period).open()
+    }
+
+    pub fn from_config(config: &Config, cycle_period: Duration) -> Result<Self, io::Error> {
+        EtherCatControllerBuilder::from_config(config, cycle_period).open()
+    }
+
+    /// Opens the master from an already parsed ESI, the ESI path of
+    /// `ethercat_config` being ignored.
+    pub fn from_esi(
+        esi: EtherCatInfo,
+        ethercat_config: &EthercatConfig,
         cycle_period: Duration,
     ) -> Result<Self, io::Error> {
-        let (mut master, domain_idx, offsets) = init_master(filename, master_id)?;
-
-        master.activate()?;
-
-        for (s, o) in &offsets {
-            log::debug!("PDO offsets of Slave {}:", u16::from(*s));
-            for (name, (pdo, bit_len, offset)) in o {
-                log::debug!(
-                    " - \"{}\" : {:X}:{:X} - {:?}, bit length: {}",
-                    name,
-                    u16::from(pdo.idx),
-                    u8::from(pdo.sub_idx),
-                    offset,
-                    bit_len
-                );
-            }
+        EtherCatControllerBuilder::from_esi(esi, ethercat_config.master_id, cycle_period)
+            .ethercat_config(ethercat_config)
+            .open()
+    }
+
+    pub(crate) fn open_with(mut builder: EtherCatControllerBuilder) -> Result<Self, io::Error> {
+        let log_target = log_target(builder.master_id, builder.label.as_deref());
+
+        for slave_id in &builder.duplicate_slaves {
+            log::warn!(
+                target: &log_target,
+                "Slave {} is configured twice, keeping the last one",
+                slave_id
+            );
         }
 
-        let data_lock = Arc::new(RwLock::new(None));
-        let write_data_lock = Arc::clone(&data_lock);
+        let esi = load_esi(builder.esi.clone())?;
+        let mut configurator = MasterConfigurator::new(builder.master_id);
+        let (mut offsets, slave_names) = configurator.configure(
+            &log_target,
+            &esi,
+            &builder.pdo_assignments,
+            &builder.watchdogs,
+        )?;
 
-        let ready_condvar = Arc::new((Mutex::new(false), Condvar::new()));
-        let write_ready_condvar = Arc::clone(&ready_condvar);
+        check_slave_configs(&builder.slaves, &offsets)?;
+        apply_register_types(&log_target, &mut offsets, &builder.register_types);
 
-        let cycle_condvar = Arc::new((Mutex::new(false), Condvar::new()));
-        let write_cycle_condvar = Arc::clone(&cycle_condvar);
+        bring_up(
+            &log_target,
+            &mut configurator,
+            offsets.slaves(),
+            builder.on_preop.take(),
+            |configurator| configurator.activate(),
+        )?;
+        let bus = configurator.into_bus()?;
+        let domain_size = bus.master.domain(bus.domain_idx).size()?;
+        log::debug!(target: &log_target, "Domain size: {} bytes", domain_size);
+        check_domain_size(&log_target, domain_size, offsets.expected_domain_size())?;
+        log::debug!(
+            target: &log_target,
+            "{} registers, {} entries",
+            offsets.total_register_count(),
+            offsets.total_entry_count()
+        );
 
-        let (tx, rx) = sync_channel::<(Range<usize>, Vec<u8>)>(5);
+        for slave_id in offsets.slaves() {
+            log::debug!(target: &log_target, "PDO offsets of Slave {}:", slave_id);
+            for name in offsets.registers(slave_id) {
+                for (index, layout) in offsets.entries(slave_id, name).iter().enumerate() {
+                    log::debug!(
+                        target: &log_target,
+                        " - \"{}\"[{}] : {:X}:{:X} - byte {} bit {}, bit length: {}, {:?}",
+                        name,
+                        index,
+                        u16::from(layout.pdo_entry_idx.idx),
+                        u8::from(layout.pdo_entry_idx.sub_idx),
+                        layout.byte_offset,
+                        layout.bit_offset,
+                        layout.bit_len,
+                        layout.direction
+                    );
+                }
+            }
+        }
 
-        let mut is_ready = false;
+        Self::start(builder, Box::new(bus), offsets, domain_size, slave_names)
+    }
 
-        thread::spawn(move || loop {
-            master.receive().unwrap();
-            master.domain(domain_idx).process().unwrap();
-            master.domain(domain_idx).queue().unwrap();
+    /// Starts the cyclic task over the configured bus.
+    pub(crate) fn start(
+        settings: EtherCatControllerBuilder,
+        bus: Box<dyn Bus>,
+        offsets: OffsetMap,
+        domain_size: usize,
+        slave_names: HashMap<u16, String>,
+    ) -> Result<Self, io::Error> {
+        let EtherCatControllerBuilder {
+            master_id,
+            cycle_period,
+            slaves,
+            initial_outputs,
+            data_logging,
+            shutdown_outputs,
+            manual_cycle,
+            lenient_writes,
+            allow_input_writes,
+            ready_timeout,
+            response_timeout,
+            ready_stable_cycles,
+            histogram_edges,
+            update_divisors,
+            label,
+            ..
+        } = settings.settings();
+        let log_target = log_target(master_id, label.as_deref());
 
-            let data = master.domain_data(domain_idx).unwrap();
+        let data_snapshot = Arc::new(Snapshot::default());
+        let output_snapshot = Arc::new(Snapshot::default());
+        let ready_condvar = Arc::new((Mutex::new(false), Condvar::new()));
+        let cycle_condvar = Arc::new((Mutex::new(0), Condvar::new()));
+        let response_condvar = Arc::new((Mutex::new(0), Condvar::new()));
 
-            log::debug!("{:?}", &data);
+        let (tx, rx) = WriteSender::channel(manual_cycle);
 
-            if let Ok(mut write_guard) = write_data_lock.write() {
-                *write_guard = Some(data.to_vec());
-            }
+        let outputs_enabled: Arc<HashMap<u16, AtomicBool>> = Arc::new(
+            offsets
+                .slaves()
+                .into_iter()
+                .map(|slave_id| (slave_id, AtomicBool::new(true)))
+                .collect(),
+        );
 
-            {
-                let (lock, cvar) = &*write_cycle_condvar;
-                let mut next_cycle = lock.lock().unwrap();
-                *next_cycle = true;
-                cvar.notify_one();
-            }
+        let master_counters = Arc::new(MasterCounters::default());
+        let histogram = Arc::new(match histogram_edges {
+            Some(edges) => CycleHistogram::new(edges),
+            None => CycleHistogram::for_period(cycle_period),
+        });
+        master_counters.reserved.store(true, Ordering::Relaxed);
 
-            while let Ok((reg_addr_range, value)) = rx.try_recv() {
-                data[reg_addr_range].copy_from_slice(&value);
-            }
+        let slave_states = Arc::new(RwLock::new(HashMap::new()));
 
-            master.send().unwrap();
+        let initial_writes = initial_outputs
+            .iter()
+            .map(|output| {
+                let layout = offsets
+                    .entry(output.slave_id, &output.register, output.index)
+                    .filter(|layout| layout.direction == Direction::Output)
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!(
+                                "Unknown output register \"{}\"[{}] for slave {}",
+                                output.register, output.index, output.slave_id
+                            ),
+                        )
+                    })?;
+                if layout.byte_width() != output.value.len() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "Register \"{}\"[{}] of slave {} is {} bytes wide, initial value is {} bytes",
+                            output.register,
+                            output.index,
+                            output.slave_id,
+                            layout.byte_width(),
+                            output.value.len()
+                        ),
+                    ));
+                }
+                Ok(PdoWrite::new(
+                    output.slave_id,
+                    layout.byte_range(),
+                    output.value.clone(),
+                ))
+            })
+            .collect::<Result<Vec<_>, io::Error>>()?;
 
-            if !is_ready {
-                let m_state = master.state().unwrap();
-                log::debug!("Current state {:?}", m_state);
+        let shutdown_writes = match &shutdown_outputs {
+            ShutdownBehavior::SafeValue(values) => values
+                .iter()
+                .map(|(slave_id, register, index, value)| {
+                    output_write(&offsets, *slave_id, register, *index, value, "safe value")
+                })
+                .collect::<Result<Vec<_>, io::Error>>()?,
+            ShutdownBehavior::Zero | ShutdownBehavior::Hold => Vec::new(),
+        };
 
-                if m_state.link_up {
-                    let (lock, cvar) = &*write_ready_condvar;
-                    let mut ready = lock.lock().unwrap();
-                    *ready = true;
-                    cvar.notify_one();
-                    is_ready = true;
+        let events = Arc::new(EventBus::default());
 
-                    log::info!("Master ready!");
-                }
-            }
+        let task = CycleTask {
+            log_target: log_target.clone(),
+            bus,
+            rx,
+            data_publisher: Publisher::new(Arc::clone(&data_snapshot)),
+            output_publisher: Publisher::new(Arc::clone(&output_snapshot)),
+            ready_condvar: Arc::clone(&ready_condvar),
+            cycle_condvar: Arc::clone(&cycle_condvar),
+            response_condvar: Arc::clone(&response_condvar),
+            response_timeout,
+            counters: Arc::clone(&master_counters),
+            outputs_enabled: Arc::clone(&outputs_enabled),
+            histogram: Arc::clone(&histogram),
+            slave_states: Arc::clone(&slave_states),
+            slave_positions: offsets.slaves().into_iter().map(SlavePos::from).collect(),
+            last_state_poll: None,
+            events: Arc::clone(&events),
+            data_logger: DataLogger::new(log_target.clone(), data_logging, &offsets),
+            shutdown_outputs,
+            shutdown_writes,
+            initial_writes,
+            update_divisors,
+            deferred_writes: Vec::new(),
+            received_batches: 0,
+            is_ready: false,
+            ready_stable_cycles,
+            ready_streak: 0,
+            cycle: 0,
+        };
 
-            thread::sleep(cycle_period);
-        });
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let last_error = Arc::new(Mutex::new(None));
+
+        let (cycle_thread, manual_task) = if manual_cycle {
+            log::info!(target: &log_target, "Manual cycle mode, the bus only runs on step_cycle");
+            (None, Some(Mutex::new(task)))
+        } else {
+            let cycle_thread = spawn_cycle_thread(
+                task,
+                master_id,
+                cycle_period,
+                Arc::clone(&stop_flag),
+                Arc::clone(&last_error),
+            )?;
+            (Some(cycle_thread), None)
+        };
 
-        Ok(EtherCatController {
+        let controller = EtherCatController {
+            master_id,
+            log_target,
+            cycle_period,
             offsets,
-            data_lock,
+            domain_size,
+            data_snapshot,
+            output_snapshot,
             ready_condvar,
             cycle_condvar,
+            response_condvar,
             cmd_buff: tx,
-        })
-    }
+            outputs_enabled,
+            master_counters,
+            histogram,
+            events,
+            slave_states,
+            stop_flag,
+            cycle_thread,
+            manual_task,
+            last_error,
+            slaves,
+            lenient_writes,
+            allow_input_writes,
+            settings,
+            slave_names,
+        };
 
-    pub fn get_slave_ids(&self) -> Vec<u16> {
-        let mut ids: Vec<u16> = self
-            .offsets
-            .keys()
-            .map(|slave_pos| u16::from(*slave_pos))
-            .collect();
-        ids.sort();
-        ids
+        if let Some(timeout) = ready_timeout.filter(|_| !manual_cycle) {
+            controller.wait_for_op(timeout)?;
+        }
+
+        Ok(controller)
     }
 
-    pub fn get_pdo_register(&self, slave_id: u16, register: &String) -> Option<Vec<u8>> {
-        let reg_addr_range = self.get_reg_addr_range(slave_id, register);
+    pub(crate) fn dry_run_with(
+        builder: EtherCatControllerBuilder,
+    ) -> Result<ConfigurationPlan, io::Error> {
+        let esi = load_esi(builder.esi.clone())?;
 
-        (*self.data_lock.read().unwrap())
-            .as_ref()
-            .map(|data| data[reg_addr_range].to_vec())
+        // Releasing the master discards its configuration, the slaves stay
+        // in PREOP.
+        dry_run_on(
+            &mut MasterConfigurator::new(builder.master_id),
+            &builder,
+            &esi,
+        )
     }
 
-    pub fn set_pdo_register(&self, slave_id: u16, register: &String, value: Vec<u8>) {
-        let reg_addr_range = self.get_reg_addr_range(slave_id, register);
+    fn wait_for_op(&self, timeout: Duration) -> Result<(), io::Error> {
+        let deadline = Instant::now() + timeout;
 
-        self.cmd_buff.send((reg_addr_range, value)).unwrap();
-    }
+        loop {
+            let link_up = *self
+                .ready_condvar
+                .0
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            let not_in_op = slaves_not_in_op(
+                self.get_slave_ids()
+                    .into_iter()
+                    .map(|slave_id| (slave_id, self.slave_state(slave_id))),
+            );
 
-    pub fn wait_for_next_cycle(&self) {
-        let (lock, cvar) = &*self.cycle_condvar;
-        let mut next_cycle = lock.lock().unwrap();
+            if link_up && not_in_op.is_empty() {
+                log::info!(target: &self.log_target, "All slaves in OP");
+                return Ok(());
+            }
 
-        *next_cycle = false;
-        while !*next_cycle {
-            next_cycle = cvar.wait(next_cycle).unwrap();
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() || !self.wait_for_next_cycle_timeout(remaining) {
+                let message = op_timeout_message(self.master_id, timeout, link_up, &not_in_op);
+                log::error!(target: &self.log_target, "{}", message);
+                return Err(io::Error::new(io::ErrorKind::TimedOut, message));
+            }
         }
     }
 
-    pub fn wait_for_ready(self) -> Self {
-        {
-            let (lock, cvar) = &*self.ready_condvar;
-            let mut ready = lock.lock().unwrap();
+    /// Stops the cyclic task after a final cycle applying the configured
+    /// shutdown outputs, then deactivates the master.
+    pub fn stop(&mut self) {
+        self.stop_flag.store(true, Ordering::Relaxed);
 
-            *ready = false;
-            while !*ready {
-                ready = cvar.wait(ready).unwrap();
+        if let Some(cycle_thread) = self.cycle_thread.take() {
+            if cycle_thread.join().is_err() {
+                log::error!(target: &self.log_target, "Cyclic task panicked");
             }
         }
-        self
+        if let Some(task) = self.manual_task.take() {
+            task.into_inner()
+                .unwrap_or_else(PoisonError::into_inner)
+                .shutdown();
+        }
+    }
+
+    /// Stops the bus, configures it again from the ESI file and restarts it
+    /// with the same settings, returning how the PDO layout changed. The
+    /// PREOP hook is not run again. If the bus cannot be brought back up the
+    /// controller is left stopped.
+    pub fn rescan(&mut self) -> Result<Vec<LayoutChange>, io::Error> {
+        self.rescan_with(EtherCatController::open_with)
     }
 
-    fn get_reg_addr_range(&self, slave_id: u16, register: &String) -> Range<usize> {
-        let slave_pos = SlavePos::from(slave_id);
+    /// Same as `rescan`, reopening the controller with `open`.
+    fn rescan_with<F>(&mut self, open: F) -> Result<Vec<LayoutChange>, io::Error>
+    where
+        F: FnOnce(EtherCatControllerBuilder) -> Result<Self, io::Error>,
+    {
+        log::info!(target: &self.log_target, "Rescanning master {}", self.master_id);
+        self.stop();
+
+        let reopened = open(self.settings.settings())?;
+        let changes = reopened.offsets.compare(&self.offsets);
+        for change in &changes {
+            log::info!(target: &self.log_target, "Layout change: {:?}", change);
+        }
 
-        let (_pdo_entry_idx, bit_len, offset) = self.offsets[&slave_pos][register];
-        let addr = offset.byte;
-        let bytes_len = (bit_len / 8) as usize;
+        *self = reopened;
 
-        addr..addr + bytes_len
+        Ok(changes)
     }
-}
 
-type SlaveOffsets = HashMap<SlavePos, HashMap<String, (PdoEntryIdx, u8, Offset)>>;
+    /// Runs exactly one cycle: receives the inputs, applies the queued writes
+    /// and sends the outputs. Only available in manual cycle mode.
+    pub fn step_cycle(&self) -> Result<(), io::Error> {
+        match &self.manual_task {
+            Some(task) => task.lock().unwrap_or_else(PoisonError::into_inner).step(),
+            None => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "step_cycle is only available in manual cycle mode",
+            )),
+        }
+    }
 
-pub fn init_master(
-    filename: &String,
-    idx: u32,
-) -> Result<(Master, DomainIdx, SlaveOffsets), io::Error> {
-    let mut esi_file = File::open(filename)?;
+    /// Working counter of the domain at the last cycle, `None` before the
+    /// first one.
+    pub fn domain_working_counter(&self) -> Option<u16> {
+        self.master_counters.domain_working_counter()
+    }
 
-    let mut esi_xml_str = String::new();
-    esi_file.read_to_string(&mut esi_xml_str)?;
+    pub fn master_id(&self) -> u32 {
+        self.master_id
+    }
 
-    let esi = EtherCatInfo::from_xml_str(&esi_xml_str)?;
+    /// Target of the log records of the controller, e.g.
+    /// `ethercat_controller::master0` or `ethercat_controller::master0::arm`
+    /// with a label.
+    pub fn log_target(&self) -> &str {
+        &self.log_target
+    }
 
-    let mut master = Master::open(idx, ethercat::MasterAccess::ReadWrite)?;
-    master.reserve()?;
+    /// Period of the cyclic task, ignored in manual cycle mode.
+    pub fn cycle_period(&self) -> Duration {
+        self.cycle_period
+    }
 
-    let domain_idx = master.create_domain()?;
+    /// Size of the domain image, as allocated by the master.
+    pub fn domain_size(&self) -> usize {
+        self.domain_size
+    }
 
-    let mut offsets: HashMap<SlavePos, HashMap<String, (PdoEntryIdx, u8, Offset)>> = HashMap::new();
+    /// Size of the domain image needed by the registered entries.
+    pub fn expected_domain_size(&self) -> usize {
+        self.offsets.expected_domain_size()
+    }
 
-    for (dev_nr, dev) in esi.description.devices.iter().enumerate() {
-        let slave_pos = SlavePos::from(dev_nr as u16);
-        log::debug!("Request PreOp state for {:?}", slave_pos);
+    /// Expected working counter of the domain when every slave exchanges its
+    /// process data.
+    pub fn expected_domain_working_counter(&self) -> u16 {
+        self.offsets
+            .slaves()
+            .into_iter()
+            .map(|slave_id| u16::from(self.offsets.expected_working_counter(slave_id)))
+            .sum()
+    }
 
-        master.request_state(slave_pos, ethercat::AlState::PreOp)?;
+    /// Contribution to the domain working counter expected from the slave in
+    /// the state it was in at the last poll: for its outputs and inputs in
+    /// OP, only for its inputs in SAFEOP, nothing if absent or in a lower
+    /// state. The master only measures the domain-wide counter, so this is
+    /// not a measurement: comparing the sum over the slaves with
+    /// `domain_working_counter` tells whether a slave in a good state stopped
+    /// exchanging its data, not which one. `None` for an unknown slave.
+    pub fn expected_slave_contribution(&self, slave_id: u16) -> Option<u8> {
+        if !self.offsets.contains_slave(slave_id) {
+            return None;
+        }
 
-        let slave_info = master.get_slave_info(slave_pos)?;
-        log::debug!("Found device {} : {:?}", dev.name, slave_info);
+        Some(expected_contribution(
+            &self.offsets,
+            slave_id,
+            self.slave_state(slave_id),
+        ))
+    }
 
-        let slave_addr = SlaveAddr::ByPos(dev_nr as u16);
-        let slave_id = SlaveId {
-            vendor_id: esi.vendor.id,
-            product_code: dev.product_code,
-        };
+    /// Why the cyclic task stopped, if it failed. The last published data
+    /// stays readable.
+    pub fn last_error(&self) -> Option<String> {
+        read_last_error(&self.last_error)
+    }
 
-        let mut config = master.configure_slave(slave_addr, slave_id)?;
-        let mut entry_offsets: HashMap<String, (PdoEntryIdx, u8, Offset)> = HashMap::new();
+    pub fn get_slave_ids(&self) -> Vec<u16> {
+        self.offsets.slaves()
+    }
+
+    /// Watchdog behavior the slave was configured with.
+    pub fn watchdog_behavior(&self, slave_id: u16) -> WatchdogBehavior {
+        self.settings
+            .watchdogs
+            .get(&slave_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Unique name of the slave, from its SII.
+    pub fn get_slave_name(&self, slave_id: u16) -> Option<String> {
+        self.slave_names.get(&slave_id).cloned()
+    }
+
+    /// Same as `get_slave_name`, falling back to "slave_<id>".
+    pub fn slave_name_or_default(&self, slave_id: u16) -> String {
+        self.get_slave_name(slave_id)
+            .unwrap_or_else(|| default_slave_name(slave_id))
+    }
 
-        let rx_pdos: Vec<PdoCfg> = dev
-            .rx_pdo
+    pub fn get_slave_id(&self, name: &str) -> Option<u16> {
+        self.slave_names
             .iter()
-            .map(|pdo| PdoCfg {
-                idx: pdo.idx,
-                entries: pdo
-                    .entries
-                    .iter()
-                    .enumerate()
-                    .map(|(i, e)| PdoEntryInfo {
-                        entry_idx: e.entry_idx,
-                        bit_len: e.bit_len as u8,
-                        name: e.name.clone().unwrap_or_default(),
-                        pos: PdoEntryPos::from(i as u8),
-                    })
-                    .collect(),
-            })
-            .collect();
+            .find(|(_, slave_name)| *slave_name == name)
+            .map(|(slave_id, _)| *slave_id)
+    }
+
+    /// Layout of every registered PDO entry in the domain image.
+    pub fn offset_map(&self) -> &OffsetMap {
+        &self.offsets
+    }
+
+    /// Number of register names, summed over all the slaves.
+    pub fn total_register_count(&self) -> usize {
+        self.offsets.total_register_count()
+    }
+
+    /// Number of PDO entries, summed over all the slaves.
+    pub fn total_entry_count(&self) -> usize {
+        self.offsets.total_entry_count()
+    }
+
+    /// Diffs the current PDO layout against a map saved from a previous run,
+    /// to detect a mapping that changed with the slave firmware.
+    pub fn compare_layout(&self, previous: &OffsetMap) -> Vec<LayoutChange> {
+        self.offsets.compare(previous)
+    }
+
+    /// Reads a register from the domain image as received at the start of
+    /// the last cycle, before the writes queued for that cycle were applied.
+    pub fn get_pdo_register(&self, slave_id: u16, register: &str) -> Option<Vec<u8>> {
+        self.get_pdo_register_at(slave_id, register, 0)
+    }
+
+    /// Same as `get_pdo_register` for a name shared by several entries:
+    /// `index` selects among them, in the order they were registered (outputs
+    /// first, then inputs).
+    pub fn get_pdo_register_at(
+        &self,
+        slave_id: u16,
+        register: &str,
+        index: usize,
+    ) -> Option<Vec<u8>> {
+        let reg_addr_range = self.get_reg_addr_range(slave_id, register, index)?;
+
+        self.data_snapshot
+            .load()
+            .and_then(|data| copy_range(&self.log_target, &data, reg_addr_range))
+    }
+
+    /// Register value in the last published cycle and in the one before, to
+    /// detect edges. `None` until two cycles were published.
+    pub fn read_with_previous(
+        &self,
+        slave_id: u16,
+        register: &str,
+        index: usize,
+    ) -> Option<(Vec<u8>, Vec<u8>)> {
+        let reg_addr_range = self.get_reg_addr_range(slave_id, register, index)?;
+
+        let (current, previous) = self.data_snapshot.load_with_previous()?;
+        Some((
+            copy_range(&self.log_target, &current, reg_addr_range.clone())?,
+            copy_range(&self.log_target, &previous, reg_addr_range)?,
+        ))
+    }
+
+    /// Time elapsed since the cyclic task last published the inputs, `None`
+    /// before the first cycle. It keeps growing if the cyclic task stalls.
+    pub fn data_age(&self) -> Option<Duration> {
+        self.data_snapshot.age()
+    }
+
+    /// Whether the inputs are older than `max_age`, or were never received.
+    pub fn is_data_stale(&self, max_age: Duration) -> bool {
+        self.data_age().is_none_or(|age| age > max_age)
+    }
+
+    /// Non-blocking variant of `get_pdo_register`: returns `None` if the
+    /// register is unknown or no data was received yet. The image is swapped
+    /// atomically so reading it never blocks.
+    pub fn try_get_pdo_register(
+        &self,
+        slave_id: u16,
+        register: &str,
+        index: usize,
+    ) -> Option<Vec<u8>> {
+        let reg_addr_range = self.get_reg_addr_range(slave_id, register, index)?;
+
+        self.data_snapshot
+            .load()
+            .and_then(|data| copy_range(&self.log_target, &data, reg_addr_range))
+    }
 
-        let tx_pdos: Vec<PdoCfg> = dev
-            .tx_pdo
+    /// Same as `get_pdo_register` but `index` only counts the entries of the
+    /// given direction, so that an input is never mistaken for an output of
+    /// the same name.
+    pub fn get_pdo_register_dir(
+        &self,
+        slave_id: u16,
+        register: &str,
+        index: usize,
+        direction: Direction,
+    ) -> Option<Vec<u8>> {
+        let reg_addr_range = self
+            .offsets
+            .entries(slave_id, register)
             .iter()
-            .map(|pdo| PdoCfg {
-                idx: pdo.idx,
-                entries: pdo
-                    .entries
-                    .iter()
-                    .enumerate()
-                    .map(|(i, e)| PdoEntryInfo {
-                        entry_idx: e.entry_idx,
-                        bit_len: e.bit_len as u8,
-                        name: e.name.clone().unwrap_or_default(),
-                        pos: PdoEntryPos::from(i as u8),
-                    })
-                    .collect(),
+            .filter(|layout| layout.direction == direction)
+            .nth(index)?
+            .byte_range();
+
+        self.data_snapshot
+            .load()
+            .and_then(|data| copy_range(&self.log_target, &data, reg_addr_range))
+    }
+
+    /// Reads a register from the domain image as last sent on the bus, i.e.
+    /// including the writes applied during the last cycle.
+    pub fn get_output_pdo_register(&self, slave_id: u16, register: &str) -> Option<Vec<u8>> {
+        self.get_output_pdo_register_at(slave_id, register, 0)
+    }
+
+    /// Same as `get_output_pdo_register`, `index` selecting among the entries
+    /// sharing the same name.
+    pub fn get_output_pdo_register_at(
+        &self,
+        slave_id: u16,
+        register: &str,
+        index: usize,
+    ) -> Option<Vec<u8>> {
+        let reg_addr_range = self.get_reg_addr_range(slave_id, register, index)?;
+
+        self.output_snapshot
+            .load()
+            .and_then(|data| copy_range(&self.log_target, &data, reg_addr_range))
+    }
+
+    /// Writes to input registers are rejected with a `PermissionDenied`
+    /// error, unless the controller was built with `allow_input_writes`.
+    pub fn set_pdo_register(
+        &self,
+        slave_id: u16,
+        register: &str,
+        value: Vec<u8>,
+    ) -> Result<(), io::Error> {
+        self.set_pdo_register_at(slave_id, register, 0, value)
+    }
+
+    /// Same as `set_pdo_register`, `index` selecting among the entries sharing
+    /// the same name. Fails with `NotFound` if there is no such entry.
+    pub fn set_pdo_register_at(
+        &self,
+        slave_id: u16,
+        register: &str,
+        index: usize,
+        value: Vec<u8>,
+    ) -> Result<(), io::Error> {
+        let layout = self
+            .offsets
+            .entry(slave_id, register, index)
+            .ok_or_else(|| unknown_register(slave_id, register, index))?;
+
+        let target = || format!("\"{}\"[{}] of slave {}", register, index, slave_id);
+        self.check_writable(&layout, target)?;
+        check_byte_aligned(&layout, target)?;
+
+        self.send_writes(vec![PdoWrite::new(slave_id, layout.byte_range(), value)]);
+
+        Ok(())
+    }
+
+    /// Reads an entry addressed by its object dictionary index rather than its
+    /// name, which may be blank or shared. `None` if the entry is unknown or
+    /// no data was received yet.
+    pub fn get_pdo_register_by_idx(
+        &self,
+        slave_id: u16,
+        pdo_entry_idx: PdoEntryIdx,
+        occurrence: usize,
+    ) -> Option<Vec<u8>> {
+        let layout = self
+            .offsets
+            .entry_by_idx(slave_id, pdo_entry_idx, occurrence)?;
+
+        self.data_snapshot
+            .load()
+            .and_then(|data| copy_range(&self.log_target, &data, layout.byte_range()))
+    }
+
+    /// Current value of every object mapped by the slave, keyed by its object
+    /// dictionary index. An object mapped more than once is read from its
+    /// first entry. `None` if the slave is unknown or no data was received
+    /// yet.
+    pub fn read_all_by_idx(&self, slave_id: u16) -> Option<HashMap<PdoEntryIdx, Vec<u8>>> {
+        if !self.offsets.contains_slave(slave_id) {
+            return None;
+        }
+        let data = self.data_snapshot.load()?;
+
+        self.offsets
+            .objects(slave_id)
+            .into_iter()
+            .map(|pdo_entry_idx| {
+                let layout = self.offsets.entry_by_idx(slave_id, pdo_entry_idx, 0)?;
+                Some((
+                    pdo_entry_idx,
+                    copy_range(&self.log_target, &data, layout.byte_range())?,
+                ))
             })
-            .collect();
+            .collect()
+    }
 
-        let output = SmCfg::output(2.into());
-        let input = SmCfg::input(3.into());
+    pub fn set_pdo_register_by_idx(
+        &self,
+        slave_id: u16,
+        pdo_entry_idx: PdoEntryIdx,
+        occurrence: usize,
+        value: Vec<u8>,
+    ) -> Result<(), io::Error> {
+        let layout = self
+            .offsets
+            .entry_by_idx(slave_id, pdo_entry_idx, occurrence)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!(
+                        "Unknown entry {:X}:{:X}[{}] for slave {}",
+                        u16::from(pdo_entry_idx.idx),
+                        u8::from(pdo_entry_idx.sub_idx),
+                        occurrence,
+                        slave_id
+                    ),
+                )
+            })?;
 
-        config.config_sm_pdos(output, &rx_pdos)?;
-        config.config_sm_pdos(input, &tx_pdos)?;
+        let target = || {
+            format!(
+                "{:X}:{:X}[{}] of slave {}",
+                u16::from(pdo_entry_idx.idx),
+                u8::from(pdo_entry_idx.sub_idx),
+                occurrence,
+                slave_id
+            )
+        };
+        self.check_writable(&layout, target)?;
+        check_byte_aligned(&layout, target)?;
+
+        self.send_writes(vec![PdoWrite::new(slave_id, layout.byte_range(), value)]);
+
+        Ok(())
+    }
+
+    /// The whole domain image as last sent on the bus.
+    pub fn get_output_image(&self) -> Option<Vec<u8>> {
+        self.output_snapshot.load().map(|data| data.to_vec())
+    }
+
+    /// Replaces all the outputs within the same cycle from a full domain
+    /// image. Only the bytes of the output entries are used, the inputs are
+    /// left untouched.
+    pub fn set_output_image(&self, bytes: Vec<u8>) -> Result<(), io::Error> {
+        if bytes.len() != self.domain_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Output image is {} bytes long but the domain is {} bytes",
+                    bytes.len(),
+                    self.domain_size
+                ),
+            ));
+        }
 
-        for pdo in &rx_pdos {
-            for entry in &pdo.entries {
-                let offset = config.register_pdo_entry(entry.entry_idx, domain_idx)?;
-                entry_offsets.insert(entry.name.clone(), (entry.entry_idx, entry.bit_len, offset));
+        self.send_writes(
+            self.offsets
+                .iter()
+                .filter(|(_, _, _, layout)| layout.direction == Direction::Output)
+                .map(|(slave_id, _, _, layout)| {
+                    let range = layout.byte_range();
+                    PdoWrite::new(slave_id, range.clone(), bytes[range].to_vec())
+                })
+                .collect(),
+        );
+
+        Ok(())
+    }
+
+    /// Reads all the entries registered under the same name, by index.
+    pub fn get_pdo_registers(&self, slave_id: u16, register: &str) -> Option<Vec<Vec<u8>>> {
+        let data = self.data_snapshot.load()?;
+
+        self.offsets
+            .entries(slave_id, register)
+            .iter()
+            .map(|layout| copy_range(&self.log_target, &data, layout.byte_range()))
+            .collect()
+    }
+
+    /// Writes one value per entry registered under the same name, within the
+    /// same cycle. Fails if the number of values does not match the number of
+    /// entries, unless the controller was built with lenient writes, in which
+    /// case the extra values or entries are skipped.
+    pub fn set_pdo_registers(
+        &self,
+        slave_id: u16,
+        register: &str,
+        values: Vec<Vec<u8>>,
+    ) -> Result<(), io::Error> {
+        let entries = self.offsets.entries(slave_id, register);
+
+        if values.len() != entries.len() {
+            let message = format!(
+                "Register \"{}\" of slave {} has {} entries, got {} values",
+                register,
+                slave_id,
+                entries.len(),
+                values.len()
+            );
+            if !self.lenient_writes {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
             }
+            log::warn!(target: &self.log_target, "{}", message);
+        }
+
+        for (index, layout) in entries.iter().enumerate() {
+            self.check_writable(layout, || {
+                format!("\"{}\"[{}] of slave {}", register, index, slave_id)
+            })?;
+        }
+
+        self.send_writes(
+            entries
+                .iter()
+                .zip(values)
+                .map(|(layout, value)| PdoWrite::new(slave_id, layout.byte_range(), value))
+                .collect(),
+        );
+
+        Ok(())
+    }
+
+    /// Fails if the entry is an input, which the slave overwrites at every
+    /// cycle, unless input writes are allowed. `target` describes the entry.
+    pub(crate) fn check_writable<F>(&self, layout: &EntryLayout, target: F) -> Result<(), io::Error>
+    where
+        F: FnOnce() -> String,
+    {
+        check_writable(&self.log_target, self.allow_input_writes, layout, target)
+    }
+
+    /// Queues writes that are all applied within the same cycle.
+    pub(crate) fn send_writes(&self, writes: Vec<PdoWrite>) {
+        if self.cmd_buff.send(writes).is_err() {
+            log::warn!(target: &self.log_target, "Cyclic task is not running, dropping writes");
+            return;
         }
-        for pdo in &tx_pdos {
-            for entry in &pdo.entries {
-                let offset = config.register_pdo_entry(entry.entry_idx, domain_idx)?;
-                entry_offsets.insert(entry.name.clone(), (entry.entry_idx, entry.bit_len, offset));
+        self.master_counters
+            .write_batches_queued
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Blocks until every write queued so far has been applied and sent on
+    /// the bus, or fails once `timeout` expires.
+    pub fn flush_writes(&self, timeout: Duration) -> Result<(), io::Error> {
+        let queued = self
+            .master_counters
+            .write_batches_queued
+            .load(Ordering::Relaxed);
+        let deadline = Instant::now() + timeout;
+
+        let mut applied = false;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() || !self.wait_for_next_cycle_timeout(remaining) {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Timed out flushing the pending writes",
+                ));
             }
+            // The writes may have been applied during the cycle that just
+            // started, so wait for the next one to be sure they were sent.
+            if applied {
+                return Ok(());
+            }
+            applied = self
+                .master_counters
+                .write_batches_applied
+                .load(Ordering::Relaxed)
+                >= queued;
         }
+    }
 
-        let cfg_index = config.index();
-        let cfg_info = master.get_config_info(cfg_index)?;
-        log::debug!("Config info: {:#?}", cfg_info);
-        if cfg_info.slave_position.is_none() {
+    /// Reads a register, failing if it is unknown, if no data has been
+    /// received yet or if the slave is not responding anymore.
+    pub fn read_pdo_register(
+        &self,
+        slave_id: u16,
+        register: &str,
+        index: usize,
+    ) -> Result<Vec<u8>, io::Error> {
+        if self.offsets.entry(slave_id, register, index).is_none() {
+            return Err(unknown_register(slave_id, register, index));
+        }
+        if !self.is_slave_present(slave_id) {
             return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Unable to configure slave",
+                io::ErrorKind::NotConnected,
+                format!("Slave {} is absent", slave_id),
             ));
         }
-        offsets.insert(slave_pos, entry_offsets);
+
+        self.get_pdo_register_at(slave_id, register, index)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::WouldBlock, "No data received yet"))
+    }
+
+    /// Whether the slave was found on the bus at the last state poll.
+    pub fn is_slave_present(&self, slave_id: u16) -> bool {
+        self.slave_state(slave_id).is_some()
+    }
+
+    /// AL state of the slave at the last state poll, `None` if it is absent.
+    pub fn slave_state(&self, slave_id: u16) -> Option<AlState> {
+        self.slave_states
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&slave_id)
+            .copied()
+            .flatten()
+    }
+
+    /// Parks a slave: while disabled, the writes queued for it are discarded
+    /// and its outputs keep their last value. Write the desired safe values
+    /// before disabling it if they should differ.
+    pub fn set_slave_output_enabled(&self, slave_id: u16, enabled: bool) {
+        match self.outputs_enabled.get(&slave_id) {
+            Some(flag) => flag.store(enabled, Ordering::Relaxed),
+            None => log::warn!(target: &self.log_target, "Unknown slave {}", slave_id),
+        }
+    }
+
+    pub fn is_slave_output_enabled(&self, slave_id: u16) -> bool {
+        self.outputs_enabled
+            .get(&slave_id)
+            .is_some_and(|flag| flag.load(Ordering::Relaxed))
+    }
+
+    pub fn wait_for_next_cycle(&self) {
+        let (lock, cvar) = &*self.cycle_condvar;
+        let published_cycles = lock.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let current = *published_cycles;
+        let _published_cycles = cvar
+            .wait_while(published_cycles, |cycles| *cycles == current)
+            .unwrap_or_else(PoisonError::into_inner);
+    }
+
+    /// Same as `wait_for_next_cycle` but gives up after `timeout`, returning
+    /// whether a new cycle was published.
+    pub fn wait_for_next_cycle_timeout(&self, timeout: Duration) -> bool {
+        let (lock, cvar) = &*self.cycle_condvar;
+        let published_cycles = lock.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let current = *published_cycles;
+        let (_published_cycles, result) = cvar
+            .wait_timeout_while(published_cycles, timeout, |cycles| *cycles == current)
+            .unwrap_or_else(PoisonError::into_inner);
+
+        !result.timed_out()
+    }
+
+    /// In synchronous control mode, tells the cyclic task that the writes
+    /// responding to the last published data are all queued, so that it
+    /// sends them without waiting any longer. Does nothing otherwise.
+    pub fn commit_writes(&self) {
+        let published_cycles = *self
+            .cycle_condvar
+            .0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        let (lock, cvar) = &*self.response_condvar;
+        let mut responded = lock.lock().unwrap_or_else(PoisonError::into_inner);
+        *responded = published_cycles;
+        cvar.notify_one();
+    }
+
+    /// Flushes the queued writes and returns the number of published cycles,
+    /// the last one holding inputs received after the writes were sent.
+    pub fn round_trip(&self, timeout: Duration) -> Result<u64, io::Error> {
+        self.flush_writes(timeout)?;
+
+        Ok(*self
+            .cycle_condvar
+            .0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Waits, re-evaluating `predicate` on the register value at each cycle,
+    /// until it is satisfied or `timeout` expires. Fails straight away with
+    /// `NotFound` if the register is unknown.
+    pub fn wait_until<F>(
+        &self,
+        slave_id: u16,
+        register: &str,
+        index: usize,
+        predicate: F,
+        timeout: Duration,
+    ) -> Result<(), io::Error>
+    where
+        F: Fn(&[u8]) -> bool,
+    {
+        let reg_addr_range = self
+            .offsets
+            .entry(slave_id, register, index)
+            .ok_or_else(|| unknown_register(slave_id, register, index))?
+            .byte_range();
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let value = self
+                .data_snapshot
+                .load()
+                .and_then(|data| copy_range(&self.log_target, &data, reg_addr_range.clone()));
+            if let Some(value) = value {
+                if predicate(&value) {
+                    return Ok(());
+                }
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() || !self.wait_for_next_cycle_timeout(remaining) {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "Timed out waiting on register \"{}\"[{}] of slave {}",
+                        register, index, slave_id
+                    ),
+                ));
+            }
+        }
+    }
+
+    /// Blocks until the master is ready, returning straight away if it
+    /// already is, e.g. when `open` waited for it with a ready timeout.
+    pub fn wait_for_ready(self) -> Self {
+        wait_ready(&self.ready_condvar);
+        self
+    }
+
+    /// Whether this controller currently holds the exclusive reservation of
+    /// its master.
+    pub fn is_reserved(&self) -> bool {
+        self.master_counters.reserved.load(Ordering::Relaxed)
+    }
+
+    pub fn master_metrics(&self) -> MasterMetrics {
+        self.master_counters.snapshot()
+    }
+
+    pub fn metrics_snapshot(&self) -> MetricsSnapshot {
+        let slave_ids = self.get_slave_ids();
+
+        MetricsSnapshot {
+            master: self.master_metrics(),
+            timing: self.timing_stats(),
+            domain_working_counter: self.domain_working_counter(),
+            expected_domain_working_counter: self.expected_domain_working_counter(),
+            slaves_present: slave_ids
+                .iter()
+                .filter(|slave_id| self.is_slave_present(**slave_id))
+                .count(),
+            slaves_total: slave_ids.len(),
+        }
+    }
+
+    pub fn timing_stats(&self) -> TimingStats {
+        self.master_counters.timing_stats()
+    }
+
+    /// Number of cycles by work duration, as `(upper edge, count)` with the
+    /// last bucket unbounded (`Duration::MAX`). The work excludes the sleep
+    /// until the next cycle.
+    pub fn timing_histogram(&self) -> Vec<(Duration, u64)> {
+        self.histogram.snapshot()
+    }
+
+    /// Subscribes to the bus events. The events are queued until read, so
+    /// the receiver should be drained or dropped.
+    pub fn subscribe_events(&self) -> Receiver<BusEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn sdo_read(
+        &self,
+        slave_id: u16,
+        index: u16,
+        sub_index: u8,
+        len: usize,
+    ) -> Result<Vec<u8>, io::Error> {
+        let master = Master::open(self.master_id, MasterAccess::ReadWrite)?;
+
+        sdo_upload(&master, slave_id, index, sub_index, len)
+    }
+
+    pub fn sdo_write(
+        &self,
+        slave_id: u16,
+        index: u16,
+        sub_index: u8,
+        value: &[u8],
+    ) -> Result<(), io::Error> {
+        let mut master = Master::open(self.master_id, MasterAccess::ReadWrite)?;
+
+        sdo_download(&mut master, slave_id, index, sub_index, value)
+    }
+
+    /// Configuration the slave was given at construction, if any. Every
+    /// configured id is checked against the bus when opening.
+    pub fn slave_config(&self, slave_id: u16) -> Option<SlaveConfig> {
+        self.slaves.get(&slave_id).cloned()
+    }
+
+    pub fn epos_params(&self, slave_id: u16) -> Option<EposKind> {
+        self.epos_config(slave_id).cloned()
+    }
+
+    pub(crate) fn epos_config(&self, slave_id: u16) -> Option<&EposKind> {
+        match self.slaves.get(&slave_id) {
+            Some(SlaveConfig::Epos(epos)) => Some(epos),
+            _ => None,
+        }
     }
 
-    Ok((master, domain_idx, offsets))
+    pub(crate) fn get_reg_addr_range(
+        &self,
+        slave_id: u16,
+        register: &str,
+        index: usize,
+    ) -> Option<Range<usize>> {
+        self.offsets.byte_range(slave_id, register, index)
+    }
+}
+
+/// Name of the slave as reported by its SII, made unique by appending the
+/// position when several slaves share it.
+fn unique_slave_name(
+    log_target: &str,
+    names: &HashMap<u16, String>,
+    slave_id: u16,
+    name: &str,
+) -> String {
+    if name.is_empty() {
+        return default_slave_name(slave_id);
+    }
+    if names.values().any(|other| other == name) {
+        let unique = format!("{}_{}", name, slave_id);
+        log::warn!(
+            target: log_target,
+            "Slave {} is also named \"{}\", renamed to \"{}\"",
+            slave_id,
+            name,
+            unique
+        );
+        return unique;
+    }
+    name.to_string()
+}
+
+fn default_slave_name(slave_id: u16) -> String {
+    format!("slave_{}", slave_id)
+}
+
+impl Drop for EtherCatController {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn spawn_cycle_thread(
+    mut task: CycleTask,
+    master_id: u32,
+    cycle_period: Duration,
+    stop_flag: Arc<AtomicBool>,
+    last_error: Arc<Mutex<Option<String>>>,
+) -> Result<JoinHandle<()>, io::Error> {
+    let counters = Arc::clone(&task.counters);
+    let log_target = task.log_target.clone();
+    let record_error = move |message: String| {
+        log::error!(target: &log_target, "{}", message);
+        *last_error.lock().unwrap_or_else(PoisonError::into_inner) = Some(message);
+    };
+
+    thread::Builder::new()
+        .name(format!("ethercat-master-{}", master_id))
+        .spawn(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                // Cycles are scheduled on absolute deadlines so that the
+                // cycle work does not make the period drift.
+                let mut deadline = Instant::now() + cycle_period;
+                while !stop_flag.load(Ordering::Relaxed) {
+                    if let Err(e) = task.step() {
+                        record_error(format!("Cyclic task failed: {}", e));
+                        break;
+                    }
+
+                    let now = Instant::now();
+                    if now > deadline {
+                        task.overrun(now - deadline);
+                        deadline = now + cycle_period;
+                    } else {
+                        thread::sleep(deadline - now);
+                        deadline += cycle_period;
+                    }
+                }
+                task.shutdown();
+            }));
+
+            if let Err(payload) = result {
+                let message = payload
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                // The master was released when the task was dropped.
+                counters.reserved.store(false, Ordering::Relaxed);
+                record_error(format!("Cyclic task panicked: {}", message));
+            }
+        })
+}
+
+/// Copies a register out of the domain image, logging instead of panicking
+/// if it lies beyond it.
+fn copy_range(log_target: &str, data: &[u8], range: Range<usize>) -> Option<Vec<u8>> {
+    let value = data.get(range.clone()).map(<[u8]>::to_vec);
+    if value.is_none() {
+        log::error!(
+            target: log_target,
+            "Register at {:?} is beyond the domain ({} bytes)",
+            range,
+            data.len()
+        );
+    }
+    value
+}
+
+fn unknown_register(slave_id: u16, register: &str, index: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!(
+            "Unknown register \"{}\"[{}] for slave {}",
+            register, index, slave_id
+        ),
+    )
+}
+
+fn read_last_error(last_error: &Mutex<Option<String>>) -> Option<String> {
+    last_error
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+// Fails loudly rather than letting the bus run unreserved, where another
+// process could grab it.
+fn reserve(log_target: &str, master: &Master, master_id: u32) -> Result<(), io::Error> {
+    reservation(log_target, master_id, master.reserve())
+}
+
+fn reservation<E: fmt::Display>(
+    log_target: &str,
+    master_id: u32,
+    result: Result<(), E>,
+) -> Result<(), io::Error> {
+    result.map_err(|e| {
+        log::error!(target: log_target, "Unable to reserve master {}: {}", master_id, e);
+        io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!(
+                "Unable to reserve master {} (already in use by another application?): {}",
+                master_id, e
+            ),
+        )
+    })
+}
+
+fn check_writable<F>(
+    log_target: &str,
+    allow_input_writes: bool,
+    layout: &EntryLayout,
+    target: F,
+) -> Result<(), io::Error>
+where
+    F: FnOnce() -> String,
+{
+    if layout.direction == Direction::Output {
+        return Ok(());
+    }
+    let message = format!(
+        "Register {} is an input, it is read-only and overwritten by the slave",
+        target()
+    );
+    if !allow_input_writes {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, message));
+    }
+    log::warn!(target: log_target, "{}", message);
+    Ok(())
+}
+
+// Writing whole bytes to an entry narrower than a byte would clobber the
+// entries packed next to it.
+fn check_byte_aligned<F>(layout: &EntryLayout, target: F) -> Result<(), io::Error>
+where
+    F: FnOnce() -> String,
+{
+    if layout.bit_offset == 0 && layout.bit_len.is_multiple_of(8) {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!(
+            "Register {} is {} bits wide at bit {}, write it with write_bools",
+            target(),
+            layout.bit_len,
+            layout.bit_offset
+        ),
+    ))
+}
+
+fn wait_ready(ready_condvar: &(Mutex<bool>, Condvar)) {
+    let (lock, cvar) = ready_condvar;
+    let ready = lock.lock().unwrap_or_else(PoisonError::into_inner);
+    let _ready = cvar
+        .wait_while(ready, |ready| !*ready)
+        .unwrap_or_else(PoisonError::into_inner);
+}
+
+/// Describes the slaves which are not in OP, from their last polled state.
+fn slaves_not_in_op(states: impl Iterator<Item = (u16, Option<AlState>)>) -> Vec<String> {
+    states
+        .filter_map(|(slave_id, state)| match state {
+            Some(AlState::Op) => None,
+            Some(state) => Some(format!("{} ({:?})", slave_id, state)),
+            None => Some(format!("{} (absent)", slave_id)),
+        })
+        .collect()
+}
+
+fn op_timeout_message(
+    master_id: u32,
+    timeout: Duration,
+    link_up: bool,
+    not_in_op: &[String],
+) -> String {
+    if link_up {
+        format!(
+            "Slaves not in OP after {:?}: {}",
+            timeout,
+            not_in_op.join(", ")
+        )
+    } else {
+        format!("Link of master {} not up after {:?}", master_id, timeout)
+    }
+}
+
+fn expected_contribution(offsets: &OffsetMap, slave_id: u16, state: Option<AlState>) -> u8 {
+    match state {
+        Some(AlState::Op) => offsets.working_counter_of(slave_id, true, true),
+        Some(AlState::SafeOp) => offsets.working_counter_of(slave_id, false, true),
+        _ => 0,
+    }
+}
+
+/// Write of a value given at construction, checked against the layout of
+/// the register. `what` names the value in the errors.
+fn output_write(
+    offsets: &OffsetMap,
+    slave_id: u16,
+    register: &str,
+    index: usize,
+    value: &[u8],
+    what: &str,
+) -> Result<PdoWrite, io::Error> {
+    let reg_addr_range = offsets
+        .entry(slave_id, register, index)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Unknown register \"{}\"[{}] for slave {}",
+                    register, index, slave_id
+                ),
+            )
+        })?
+        .byte_range();
+    if reg_addr_range.len() != value.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Register \"{}\"[{}] of slave {} is {} bytes wide, {} is {} bytes",
+                register,
+                index,
+                slave_id,
+                reg_addr_range.len(),
+                what,
+                value.len()
+            ),
+        ));
+    }
+
+    Ok(PdoWrite::new(slave_id, reg_addr_range, value.to_vec()))
+}
+
+fn load_esi(source: EsiSource) -> Result<EtherCatInfo, io::Error> {
+    match source {
+        EsiSource::File(filename) => read_esi(&filename),
+        EsiSource::Info(esi) => Ok(*esi),
+    }
+}
+
+fn read_esi(filename: &str) -> Result<EtherCatInfo, io::Error> {
+    let mut esi_file = File::open(filename)?;
+
+    let mut esi_xml_str = String::new();
+    esi_file.read_to_string(&mut esi_xml_str)?;
+
+    EtherCatInfo::from_xml_str(&esi_xml_str)
+}
+
+pub fn init_master_with_esi(
+    log_target: &str,
+    esi: &EtherCatInfo,
+    idx: u32,
+    pdo_assignments: &[PdoAssignment],
+    watchdogs: &HashMap<u16, WatchdogBehavior>,
+) -> Result<(Master, DomainIdx, OffsetMap, HashMap<u16, String>), io::Error> {
+    let mut master = Master::open(idx, MasterAccess::ReadWrite)?;
+    reserve(log_target, &master, idx)?;
+
+    let domain_idx = master.create_domain()?;
+
+    let mut offsets = OffsetMap::new();
+    let mut slave_names = HashMap::new();
+
+    for (dev_nr, dev) in esi.description.devices.iter().enumerate() {
+        let slave_pos = SlavePos::from(dev_nr as u16);
+        log::debug!(target: log_target, "Request PreOp state for {:?}", slave_pos);
+
+        master.request_state(slave_pos, ethercat::AlState::PreOp)?;
+
+        let slave_info = master.get_slave_info(slave_pos)?;
+        log::debug!(target: log_target, "Found device {} : {:?}", dev.name, slave_info);
+        let slave_name =
+            unique_slave_name(log_target, &slave_names, dev_nr as u16, &slave_info.name);
+        slave_names.insert(dev_nr as u16, slave_name);
+
+        let slave_addr = SlaveAddr::ByPos(dev_nr as u16);
+        let slave_id = SlaveId {
+            vendor_id: esi.vendor.id,
+            product_code: dev.product_code,
+        };
+
+        let mut config = master.configure_slave(slave_addr, slave_id)?;
+
+        // The master writes the assignment objects (0x1C12/0x1C13) from these
+        // lists when configuring the slave in PREOP.
+        let (rx_pdos, tx_pdos) = sm_pdos(dev, pdo_assignments, dev_nr as u16)?;
+
+        let output = output_sm(watchdogs.get(&(dev_nr as u16)).copied());
+        let input = SmCfg::input(3.into());
+
+        config.config_sm_pdos(output, &rx_pdos)?;
+        config.config_sm_pdos(input, &tx_pdos)?;
+
+        for (pdos, direction) in [(&rx_pdos, Direction::Output), (&tx_pdos, Direction::Input)] {
+            for pdo in pdos {
+                for entry in &pdo.entries {
+                    let offset = config.register_pdo_entry(entry.entry_idx, domain_idx)?;
+                    let name = register_name(log_target, &offsets, dev_nr as u16, entry, direction);
+                    offsets.insert(
+                        dev_nr as u16,
+                        name,
+                        EntryLayout::new(entry.entry_idx, entry.bit_len, offset, direction)
+                            .with_data_type(esi_data_type(dev, entry.entry_idx)),
+                    );
+                }
+            }
+        }
+
+        let cfg_index = config.index();
+        let cfg_info = master.get_config_info(cfg_index)?;
+        log::debug!(target: log_target, "Config info: {:#?}", cfg_info);
+        if cfg_info.slave_position.is_none() {
+            return Err(io::Error::other("Unable to configure slave"));
+        }
+        offsets.add_slave(dev_nr as u16);
+    }
+
+    Ok((master, domain_idx, offsets, slave_names))
+}
+
+/// Configures the slaves without activating the master, and plans the
+/// configuration from the registered entries.
+fn dry_run_on(
+    configurator: &mut impl Configure,
+    builder: &EtherCatControllerBuilder,
+    esi: &EtherCatInfo,
+) -> Result<ConfigurationPlan, io::Error> {
+    let log_target = log_target(builder.master_id, builder.label.as_deref());
+
+    let (mut offsets, _) = configurator.configure(
+        &log_target,
+        esi,
+        &builder.pdo_assignments,
+        &builder.watchdogs,
+    )?;
+
+    check_slave_configs(&builder.slaves, &offsets)?;
+    apply_register_types(&log_target, &mut offsets, &builder.register_types);
+
+    let plan = configuration_plan(builder.master_id, esi, &builder.pdo_assignments, offsets)?;
+    log::info!(target: &log_target, "Dry run, nothing activated:\n{}", plan);
+
+    Ok(plan)
+}
+
+fn configuration_plan(
+    master_id: u32,
+    esi: &EtherCatInfo,
+    pdo_assignments: &[PdoAssignment],
+    offsets: OffsetMap,
+) -> Result<ConfigurationPlan, io::Error> {
+    let mut sync_managers = Vec::new();
+    for (dev_nr, dev) in esi.description.devices.iter().enumerate() {
+        let slave_id = dev_nr as u16;
+        let (rx_pdos, tx_pdos) = sm_pdos(dev, pdo_assignments, slave_id)?;
+        for (sync_manager, pdos) in [(2, rx_pdos), (3, tx_pdos)] {
+            sync_managers.push(PlannedSyncManager {
+                slave_id,
+                sync_manager,
+                pdos: pdos.iter().map(|pdo| u16::from(pdo.idx)).collect(),
+            });
+        }
+    }
+
+    Ok(ConfigurationPlan {
+        master_id,
+        sync_managers,
+        offsets,
+    })
+}
+
+fn apply_register_types(
+    log_target: &str,
+    offsets: &mut OffsetMap,
+    register_types: &[RegisterType],
+) {
+    for register_type in register_types {
+        if !offsets.set_data_type(
+            register_type.slave_id,
+            &register_type.register,
+            register_type.data_type,
+        ) {
+            log::warn!(
+                target: log_target,
+                "Unknown register \"{}\" for slave {}, data type ignored",
+                register_type.register,
+                register_type.slave_id
+            );
+        }
+    }
+}
+
+/// Fails if a slave is configured under an id which is not on the bus.
+fn check_slave_configs(
+    slaves: &HashMap<u16, SlaveConfig>,
+    offsets: &OffsetMap,
+) -> Result<(), io::Error> {
+    let mut missing: Vec<u16> = slaves
+        .keys()
+        .copied()
+        .filter(|slave_id| !offsets.contains_slave(*slave_id))
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    missing.sort();
+
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!(
+            "Configured slaves {:?} are not on the bus, which has slaves {:?}",
+            missing,
+            offsets.slaves()
+        ),
+    ))
+}
+
+/// Fails if the domain cannot hold every registered entry. A larger domain
+/// is only reported, as it may hold entries registered under no name.
+fn check_domain_size(
+    log_target: &str,
+    domain_size: usize,
+    expected: usize,
+) -> Result<(), io::Error> {
+    if domain_size < expected {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Domain is {} bytes but the registered entries need {} bytes",
+                domain_size, expected
+            ),
+        ));
+    }
+    if domain_size > expected {
+        log::warn!(
+            target: log_target,
+            "Domain is {} bytes, larger than the {} bytes of the registered entries",
+            domain_size,
+            expected
+        );
+    }
+    Ok(())
+}
+
+fn esi_data_type(dev: &Device, entry_idx: PdoEntryIdx) -> DataType {
+    dev.rx_pdo
+        .iter()
+        .chain(&dev.tx_pdo)
+        .flat_map(|pdo| &pdo.entries)
+        .find(|entry| entry.entry_idx == entry_idx)
+        .and_then(|entry| entry.data_type.as_deref())
+        .map_or(DataType::Unknown, DataType::from_esi_name)
+}
+
+/// Name under which an entry is registered. Unnamed entries get a key made
+/// of their object index (e.g. "6041:00") instead of all sharing "".
+fn register_name(
+    log_target: &str,
+    offsets: &OffsetMap,
+    slave_id: u16,
+    entry: &PdoEntryInfo,
+    direction: Direction,
+) -> String {
+    let key = format!(
+        "{:04X}:{:02X}",
+        u16::from(entry.entry_idx.idx),
+        u8::from(entry.entry_idx.sub_idx)
+    );
+
+    if entry.name.is_empty() {
+        log::warn!(
+            target: log_target,
+            "Unnamed entry {} of slave {}, registered as \"{}\"",
+            key,
+            slave_id,
+            key
+        );
+        return key;
+    }
+
+    let shared = offsets
+        .entries(slave_id, &entry.name)
+        .iter()
+        .any(|layout| layout.direction == direction && layout.pdo_entry_idx != entry.entry_idx);
+    if shared {
+        log::warn!(
+            target: log_target,
+            "Name \"{}\" of slave {} is shared by several entries ({} and others), tell them apart by index",
+            entry.name,
+            slave_id,
+            key
+        );
+    }
+
+    entry.name.clone()
+}
+
+/// PDOs of the output (SM2) and input (SM3) sync managers of the slave.
+fn sm_pdos(
+    dev: &Device,
+    pdo_assignments: &[PdoAssignment],
+    slave_id: u16,
+) -> Result<(Vec<PdoCfg>, Vec<PdoCfg>), io::Error> {
+    for assignment in pdo_assignments {
+        if assignment.slave_id == slave_id && !matches!(assignment.sync_manager, 2 | 3) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Cannot assign PDOs to SM{} of slave {}, only SM2 and SM3 are supported",
+                    assignment.sync_manager, assignment.slave_id
+                ),
+            ));
+        }
+    }
+    let assigned = |sm: u8| {
+        pdo_assignments
+            .iter()
+            .find(|a| a.slave_id == slave_id && a.sync_manager == sm)
+    };
+
+    Ok((
+        pdo_cfgs(&dev.rx_pdo, assigned(2), slave_id)?,
+        pdo_cfgs(&dev.tx_pdo, assigned(3), slave_id)?,
+    ))
+}
+
+/// Output sync manager (SM2), with the watchdog set up for the behavior.
+fn output_sm(watchdog: Option<WatchdogBehavior>) -> SmCfg {
+    let mut output = SmCfg::output(2.into());
+    output.watchdog_mode = match watchdog {
+        None | Some(WatchdogBehavior::Default) => WatchdogMode::Default,
+        Some(WatchdogBehavior::SafeState) => WatchdogMode::Enable,
+        Some(WatchdogBehavior::Hold) => WatchdogMode::Disable,
+    };
+    output
+}
+
+/// Builds the PDO configuration of a sync manager, either from all the PDOs
+/// of the ESI or from the assigned ones, in the assignment order.
+fn pdo_cfgs(
+    pdos: &[Pdo],
+    assignment: Option<&PdoAssignment>,
+    slave_id: u16,
+) -> Result<Vec<PdoCfg>, io::Error> {
+    let selected: Vec<&Pdo> = match assignment {
+        None => pdos.iter().collect(),
+        Some(assignment) => assignment
+            .pdos
+            .iter()
+            .map(|idx| {
+                pdos.iter()
+                    .find(|pdo| u16::from(pdo.idx) == *idx)
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::NotFound,
+                            format!(
+                                "PDO {:#06X} assigned to SM{} does not exist on slave {}",
+                                idx, assignment.sync_manager, slave_id
+                            ),
+                        )
+                    })
+            })
+            .collect::<Result<_, _>>()?,
+    };
+
+    Ok(selected
+        .into_iter()
+        .map(|pdo| PdoCfg {
+            idx: pdo.idx,
+            entries: pdo
+                .entries
+                .iter()
+                .enumerate()
+                .map(|(i, e)| PdoEntryInfo {
+                    entry_idx: e.entry_idx,
+                    bit_len: e.bit_len as u8,
+                    name: e.name.clone().unwrap_or_default(),
+                    pos: PdoEntryPos::from(i as u8),
+                })
+                .collect(),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::mpsc;
+
+    use ethercat::PdoEntryIdx;
+
+    use super::*;
+    use crate::{cycle::tests::MockBus, offsets::tests::layout, TypedWrite};
+
+    pub(crate) fn builder() -> EtherCatControllerBuilder {
+        EtherCatControllerBuilder::new("unused.xml", 0, Duration::from_millis(1))
+    }
+
+    /// Controller over a mock bus as large as its image, stepped manually
+    /// unless built otherwise.
+    pub(crate) fn mock_controller(
+        builder: EtherCatControllerBuilder,
+        bus: MockBus,
+        offsets: OffsetMap,
+    ) -> EtherCatController {
+        let domain_size = bus.image.len();
+
+        EtherCatController::start(builder, Box::new(bus), offsets, domain_size, HashMap::new())
+            .unwrap()
+    }
+
+    /// Slave 0 with a 16-bit output at byte 0 and a 16-bit input at byte 2.
+    pub(crate) fn output_and_input() -> OffsetMap {
+        let mut offsets = OffsetMap::new();
+        offsets.insert(
+            0,
+            "target".to_string(),
+            layout(0x7000, 16, 0, Direction::Output),
+        );
+        offsets.insert(
+            0,
+            "status".to_string(),
+            layout(0x6000, 16, 2, Direction::Input),
+        );
+        offsets
+    }
+
+    /// Slaves 0 and 1, each with an 8-bit output and an 8-bit input.
+    pub(crate) fn two_slaves() -> OffsetMap {
+        let mut offsets = OffsetMap::new();
+        for slave_id in 0..2 {
+            let byte = 2 * usize::from(slave_id);
+            offsets.insert(
+                slave_id,
+                "target".to_string(),
+                layout(0x7000, 8, byte, Direction::Output),
+            );
+            offsets.insert(
+                slave_id,
+                "status".to_string(),
+                layout(0x6000, 8, byte + 1, Direction::Input),
+            );
+        }
+        offsets
+    }
+
+    #[test]
+    fn controller_can_be_shared_between_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<EtherCatController>();
+    }
+
+    #[test]
+    fn reads_return_the_inputs_as_received() {
+        let controller = mock_controller(
+            builder().manual_cycle(true),
+            MockBus::new(4),
+            output_and_input(),
+        );
+        let target = "target";
+        assert_eq!(controller.get_pdo_register(0, target), None);
+
+        controller.step_cycle().unwrap();
+        controller.set_pdo_register(0, target, vec![1, 2]).unwrap();
+        assert_eq!(
+            controller.get_output_pdo_register(0, target),
+            Some(vec![0, 0])
+        );
+
+        // The write is applied after the inputs were published.
+        controller.step_cycle().unwrap();
+        assert_eq!(controller.get_pdo_register(0, target), Some(vec![0, 0]));
+        assert_eq!(
+            controller.get_output_pdo_register(0, target),
+            Some(vec![1, 2])
+        );
+
+        // The mock bus echoes the image it was sent.
+        controller.step_cycle().unwrap();
+        assert_eq!(controller.get_pdo_register(0, target), Some(vec![1, 2]));
+    }
+
+    #[test]
+    fn step_cycle_requires_manual_mode() {
+        let controller = mock_controller(builder(), MockBus::new(4), output_and_input());
+
+        let err = controller.step_cycle().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn writes_queued_between_steps_do_not_block() {
+        let controller = mock_controller(
+            builder().manual_cycle(true),
+            MockBus::new(4),
+            output_and_input(),
+        );
+
+        for value in 0..20 {
+            controller
+                .set_pdo_register(0, "target", vec![value, 0])
+                .unwrap();
+        }
+        controller.step_cycle().unwrap();
+        assert_eq!(
+            controller.get_output_pdo_register(0, "target"),
+            Some(vec![19, 0])
+        );
+    }
+
+    #[test]
+    fn safe_values_land_in_the_final_cycle() {
+        let bus = MockBus::new(4);
+        let sent = Arc::clone(&bus.sent);
+        let deactivated = Arc::clone(&bus.deactivated);
+        let safe_values = vec![(0, "status".to_string(), 0, vec![7, 8])];
+        let mut controller = mock_controller(
+            builder()
+                .manual_cycle(true)
+                .shutdown_outputs(ShutdownBehavior::SafeValue(safe_values)),
+            bus,
+            output_and_input(),
+        );
+
+        controller.step_cycle().unwrap();
+        controller
+            .set_pdo_register(0, "target", vec![1, 2])
+            .unwrap();
+        controller.stop();
+
+        assert_eq!(sent.lock().unwrap().last().unwrap(), &[1, 2, 7, 8]);
+        assert!(deactivated.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn shutdown_zeroes_the_outputs() {
+        let bus = MockBus::new(4);
+        let sent = Arc::clone(&bus.sent);
+        let mut controller = mock_controller(
+            builder()
+                .manual_cycle(true)
+                .shutdown_outputs(ShutdownBehavior::Zero),
+            bus,
+            output_and_input(),
+        );
+
+        controller
+            .set_pdo_register(0, "target", vec![1, 2])
+            .unwrap();
+        controller.step_cycle().unwrap();
+        controller.stop();
+
+        assert_eq!(sent.lock().unwrap().last().unwrap(), &[0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn rescan_picks_up_the_new_topology() {
+        let bus = MockBus::new(4);
+        let deactivated = Arc::clone(&bus.deactivated);
+        let mut controller = mock_controller(builder().manual_cycle(true), bus, output_and_input());
+        assert_eq!(controller.get_slave_ids(), [0]);
+
+        let changes = controller
+            .rescan_with(|settings| Ok(mock_controller(settings, MockBus::new(4), two_slaves())))
+            .unwrap();
+        assert!(deactivated.load(Ordering::Relaxed));
+
+        let changes: Vec<(&str, u16, &str)> = changes
+            .iter()
+            .map(|change| match change {
+                LayoutChange::Added {
+                    slave_id, register, ..
+                } => ("added", *slave_id, register.as_str()),
+                LayoutChange::Removed {
+                    slave_id, register, ..
+                } => ("removed", *slave_id, register.as_str()),
+                LayoutChange::Changed {
+                    slave_id, register, ..
+                } => ("changed", *slave_id, register.as_str()),
+            })
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                ("changed", 0, "status"),
+                ("changed", 0, "target"),
+                ("added", 1, "status"),
+                ("added", 1, "target"),
+            ]
+        );
+
+        // The settings are kept and the new layout is used.
+        assert_eq!(controller.get_slave_ids(), [0, 1]);
+        controller.set_pdo_register(1, "target", vec![5]).unwrap();
+        controller.step_cycle().unwrap();
+        assert_eq!(
+            controller.get_output_pdo_register(1, "target"),
+            Some(vec![5])
+        );
+    }
+
+    #[test]
+    fn identically_named_slaves_are_told_apart() {
+        let mut slave_names = HashMap::new();
+        for (slave_id, name) in ["drive", "drive", ""].into_iter().enumerate() {
+            let slave_id = slave_id as u16;
+            let unique = unique_slave_name("test", &slave_names, slave_id, name);
+            slave_names.insert(slave_id, unique);
+        }
+        let controller = EtherCatController::start(
+            builder().manual_cycle(true),
+            Box::new(MockBus::new(4)),
+            two_slaves(),
+            4,
+            slave_names,
+        )
+        .unwrap();
+
+        assert_eq!(controller.get_slave_id("drive"), Some(0));
+        assert_eq!(controller.get_slave_id("drive_1"), Some(1));
+        assert_eq!(controller.get_slave_name(1).as_deref(), Some("drive_1"));
+        assert_eq!(controller.slave_name_or_default(2), "slave_2");
+        assert_eq!(controller.get_slave_id("slave_2"), Some(2));
+        assert_eq!(controller.get_slave_name(3), None);
+        assert_eq!(controller.slave_name_or_default(3), "slave_3");
+    }
+
+    #[test]
+    fn output_image_is_applied_in_one_cycle() {
+        let mut bus = MockBus::new(4);
+        bus.image = vec![0, 9, 0, 9];
+        let sent = Arc::clone(&bus.sent);
+        let controller = mock_controller(builder().manual_cycle(true), bus, two_slaves());
+        assert_eq!(controller.get_output_image(), None);
+
+        let err = controller.set_output_image(vec![1, 2, 3]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        controller.set_output_image(vec![1, 2, 3, 4]).unwrap();
+        controller.step_cycle().unwrap();
+
+        // Both outputs land in the first cycle, the inputs are left alone.
+        assert_eq!(sent.lock().unwrap()[0], [1, 9, 3, 9]);
+        assert_eq!(controller.get_output_image(), Some(vec![1, 9, 3, 9]));
+    }
+
+    #[test]
+    fn registers_beyond_the_domain_are_not_read() {
+        let mut offsets = output_and_input();
+        offsets.insert(
+            0,
+            "stale".to_string(),
+            layout(0x6010, 16, 3, Direction::Input),
+        );
+        let controller = mock_controller(builder().manual_cycle(true), MockBus::new(4), offsets);
+        controller.step_cycle().unwrap();
+
+        assert_eq!(controller.get_pdo_register(0, "stale"), None);
+        assert_eq!(controller.get_pdo_register(0, "status"), Some(vec![0, 0]));
+    }
+
+    #[test]
+    fn writes_beyond_the_image_are_reported_and_skipped() {
+        let bus = MockBus::new(4);
+        let sent = Arc::clone(&bus.sent);
+        let controller = mock_controller(builder().manual_cycle(true), bus, output_and_input());
+        let events = controller.subscribe_events();
+
+        controller.send_writes(vec![
+            PdoWrite::new(0, 3..5, vec![1, 2]),
+            PdoWrite::new(0, 0..1, vec![3]),
+        ]);
+        controller.step_cycle().unwrap();
+
+        assert_eq!(sent.lock().unwrap().last().unwrap()[..], [3, 0, 0, 0]);
+        assert_eq!(controller.master_metrics().invalid_writes, 1);
+        match events.try_recv().unwrap() {
+            BusEvent::InvalidWrite {
+                slave_id,
+                range,
+                value_len,
+                domain_len,
+            } => {
+                assert_eq!((slave_id, range, value_len, domain_len), (0, 3..5, 2, 4));
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn initial_outputs_are_written_in_the_first_cycle() {
+        let bus = MockBus::new(4);
+        let sent = Arc::clone(&bus.sent);
+        let controller = mock_controller(
+            builder()
+                .manual_cycle(true)
+                .initial_output(0, "target", 0, vec![5])
+                .initial_output(1, "target", 0, vec![6]),
+            bus,
+            two_slaves(),
+        );
+        controller.set_pdo_register(1, "target", vec![9]).unwrap();
+
+        controller.step_cycle().unwrap();
+        controller.set_pdo_register(0, "target", vec![7]).unwrap();
+        controller.step_cycle().unwrap();
+
+        // The writes queued before the first cycle come after the initial
+        // values, which are not written again.
+        assert_eq!(sent.lock().unwrap()[0], [5, 0, 9, 0]);
+        assert_eq!(sent.lock().unwrap()[1], [7, 0, 9, 0]);
+    }
+
+    #[test]
+    fn watchdog_behavior_is_passed_to_the_sync_manager() {
+        let controller = mock_controller(
+            builder()
+                .manual_cycle(true)
+                .watchdog(1, WatchdogBehavior::Hold),
+            MockBus::new(4),
+            two_slaves(),
+        );
+        assert_eq!(controller.watchdog_behavior(0), WatchdogBehavior::Default);
+        assert_eq!(controller.watchdog_behavior(1), WatchdogBehavior::Hold);
+
+        let mode = |behavior| output_sm(behavior).watchdog_mode;
+        assert!(matches!(mode(None), WatchdogMode::Default));
+        assert!(matches!(
+            mode(Some(WatchdogBehavior::Default)),
+            WatchdogMode::Default
+        ));
+        assert!(matches!(
+            mode(Some(WatchdogBehavior::SafeState)),
+            WatchdogMode::Enable
+        ));
+        assert!(matches!(
+            mode(Some(WatchdogBehavior::Hold)),
+            WatchdogMode::Disable
+        ));
+    }
+
+    #[test]
+    fn flushed_writes_are_all_sent() {
+        let bus = MockBus::new(4);
+        let sent = Arc::clone(&bus.sent);
+        let controller = mock_controller(builder().manual_cycle(true), bus, two_slaves());
+
+        for value in 1..=5 {
+            controller
+                .set_pdo_register(0, "target", vec![value])
+                .unwrap();
+            controller
+                .set_pdo_register(1, "target", vec![10 * value])
+                .unwrap();
+        }
+        thread::scope(|scope| {
+            let flush = scope.spawn(|| controller.flush_writes(Duration::from_secs(1)));
+            while !flush.is_finished() {
+                controller.step_cycle().unwrap();
+            }
+            flush.join().unwrap().unwrap();
+        });
+
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.last().unwrap()[..], [5, 0, 50, 0]);
+    }
+
+    #[test]
+    fn round_trip_returns_a_cycle_with_the_writes_echoed() {
+        let mut bus = MockBus::new(4);
+        // The slave echoes its output in its input, and records the number of
+        // the first received frame holding the write.
+        let echoed_at = Arc::new(Mutex::new(None));
+        let echoed = Arc::clone(&echoed_at);
+        let mut received = 0;
+        bus.slaves = Some(Box::new(move |image: &mut [u8]| {
+            received += 1;
+            image.copy_within(0..2, 2);
+            let mut echoed = echoed.lock().unwrap();
+            if image[0] == 5 && echoed.is_none() {
+                *echoed = Some(received);
+            }
+        }));
+        let controller = mock_controller(builder().manual_cycle(true), bus, output_and_input());
+        controller.step_cycle().unwrap();
+
+        controller
+            .set_pdo_register(0, "target", vec![5, 0])
+            .unwrap();
+        let cycle = thread::scope(|scope| {
+            let round_trip = scope.spawn(|| controller.round_trip(Duration::from_secs(1)));
+            while !round_trip.is_finished() {
+                controller.step_cycle().unwrap();
+            }
+            round_trip.join().unwrap().unwrap()
+        });
+
+        let echoed_at = echoed_at.lock().unwrap().unwrap();
+        assert!(cycle >= echoed_at, "{} before {}", cycle, echoed_at);
+        assert_eq!(controller.get_pdo_register(0, "status"), Some(vec![5, 0]));
+    }
+
+    #[test]
+    fn flush_times_out_without_cycles() {
+        let controller =
+            mock_controller(builder().manual_cycle(true), MockBus::new(4), two_slaves());
+        controller.set_pdo_register(0, "target", vec![1]).unwrap();
+
+        let err = controller
+            .flush_writes(Duration::from_millis(10))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn responses_land_in_the_same_cycle_in_synchronous_control() {
+        let mut bus = MockBus::new(4);
+        let sent = Arc::clone(&bus.sent);
+        // The slave counts the cycles in its input.
+        let mut count = 0u8;
+        bus.slaves = Some(Box::new(move |image: &mut [u8]| {
+            count = count.wrapping_add(1);
+            image[2] = count;
+        }));
+        let controller = mock_controller(
+            builder()
+                .manual_cycle(true)
+                .synchronous_control(Duration::from_secs(10)),
+            bus,
+            output_and_input(),
+        );
+
+        thread::scope(|scope| {
+            for cycle in 1..=5 {
+                let step = scope.spawn(|| controller.step_cycle());
+                // The step waits for the response once the inputs are
+                // published.
+                let (lock, cvar) = &*controller.cycle_condvar;
+                drop(
+                    cvar.wait_while(lock.lock().unwrap(), |published| *published < cycle)
+                        .unwrap(),
+                );
+                let count = controller.get_pdo_register(0, "status").unwrap()[0];
+                controller
+                    .set_pdo_register(0, "target", vec![count, 0])
+                    .unwrap();
+                controller.commit_writes();
+                step.join().unwrap().unwrap();
+
+                // The response went out with the inputs it answered.
+                assert_eq!(sent.lock().unwrap().last().unwrap()[..3], [count, 0, count]);
+            }
+        });
+    }
+
+    #[test]
+    fn domain_too_small_for_the_entries_is_rejected() {
+        let expected = output_and_input().expected_domain_size();
+        assert_eq!(expected, 4);
+
+        check_domain_size("test", 4, expected).unwrap();
+        // A larger domain is only reported.
+        check_domain_size("test", 6, expected).unwrap();
+
+        let err = check_domain_size("test", 3, expected).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            "Domain is 3 bytes but the registered entries need 4 bytes"
+        );
+    }
+
+    #[test]
+    fn ready_waits_for_a_stable_link() {
+        let bus = MockBus::new(4);
+        let link_up = Arc::clone(&bus.link_up);
+        let controller = mock_controller(
+            builder().manual_cycle(true).ready_stable_cycles(3),
+            bus,
+            output_and_input(),
+        );
+        let is_ready = || *controller.ready_condvar.0.lock().unwrap();
+
+        for (step, up) in [true, true, false, true, true, true]
+            .into_iter()
+            .enumerate()
+        {
+            assert!(!is_ready(), "Ready before step {}", step);
+            link_up.store(up, Ordering::Relaxed);
+            controller.step_cycle().unwrap();
+        }
+        assert!(is_ready());
+
+        // Once ready, a link drop does not reset it.
+        link_up.store(false, Ordering::Relaxed);
+        controller.step_cycle().unwrap();
+        assert!(is_ready());
+    }
+
+    #[test]
+    fn totals_sum_over_the_slaves() {
+        let mut offsets = two_slaves();
+        for byte in 4..6 {
+            offsets.insert(
+                1,
+                "lword".to_string(),
+                layout(0x6010, 8, byte, Direction::Input),
+            );
+        }
+        offsets.add_slave(2);
+        let controller = mock_controller(builder().manual_cycle(true), MockBus::new(6), offsets);
+
+        // "target" and "status" of both slaves, plus "lword" of slave 1.
+        assert_eq!(controller.total_register_count(), 5);
+        assert_eq!(controller.total_entry_count(), 6);
+        assert_eq!(controller.get_slave_ids(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn configured_slaves_must_be_on_the_bus() {
+        let epos = |id| {
+            (
+                id,
+                SlaveConfig::Epos(EposKind {
+                    id,
+                    ..Default::default()
+                }),
+            )
+        };
+        let offsets = two_slaves();
+
+        check_slave_configs(&HashMap::from([epos(1)]), &offsets).unwrap();
+
+        let err =
+            check_slave_configs(&HashMap::from([epos(1), epos(4), epos(2)]), &offsets).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(
+            err.to_string(),
+            "Configured slaves [2, 4] are not on the bus, which has slaves [0, 1]"
+        );
+    }
+
+    #[test]
+    fn accessors_return_the_builder_settings() {
+        let controller = mock_controller(
+            EtherCatControllerBuilder::new("unused.xml", 7, Duration::from_micros(500))
+                .manual_cycle(true),
+            MockBus::new(4),
+            output_and_input(),
+        );
+
+        assert_eq!(controller.master_id(), 7);
+        assert_eq!(controller.cycle_period(), Duration::from_micros(500));
+    }
+
+    #[test]
+    fn divided_slave_writes_are_applied_every_other_cycle() {
+        let bus = MockBus::new(4);
+        let sent = Arc::clone(&bus.sent);
+        let controller = mock_controller(
+            builder().manual_cycle(true).update_divisor(1, 2),
+            bus,
+            two_slaves(),
+        );
+        let write = |slave_ids: &[u16], value: u8| {
+            let writes: Vec<_> = slave_ids
+                .iter()
+                .map(|slave_id| TypedWrite::U8 {
+                    slave: *slave_id,
+                    register: "target".to_string(),
+                    index: 0,
+                    value,
+                })
+                .collect();
+            controller.write_many(&writes).unwrap();
+        };
+        let applied = || {
+            controller
+                .master_counters
+                .write_batches_applied
+                .load(Ordering::Relaxed)
+        };
+        let last_sent = || sent.lock().unwrap().last().cloned().unwrap();
+
+        // Cycle 0 updates both slaves.
+        write(&[0, 1], 1);
+        controller.step_cycle().unwrap();
+        assert_eq!(last_sent(), [1, 0, 1, 0]);
+        assert_eq!(applied(), 1);
+
+        // Cycle 1 only updates slave 0, the batch waits for slave 1.
+        write(&[0, 1], 2);
+        controller.step_cycle().unwrap();
+        assert_eq!(last_sent(), [2, 0, 1, 0]);
+        assert_eq!(applied(), 1);
+
+        // A later batch is not counted before the deferred one.
+        write(&[0], 3);
+        controller.step_cycle().unwrap();
+        assert_eq!(last_sent(), [3, 0, 2, 0]);
+        assert_eq!(applied(), 3);
+
+        write(&[0, 1], 4);
+        controller.step_cycle().unwrap();
+        assert_eq!(last_sent(), [4, 0, 2, 0]);
+        controller.step_cycle().unwrap();
+        assert_eq!(last_sent(), [4, 0, 4, 0]);
+        assert_eq!(applied(), 4);
+    }
+
+    #[test]
+    fn disabled_slaves_keep_their_outputs() {
+        let controller =
+            mock_controller(builder().manual_cycle(true), MockBus::new(4), two_slaves());
+        let target = "target";
+        controller.step_cycle().unwrap();
+
+        controller.set_slave_output_enabled(1, false);
+        assert!(controller.is_slave_output_enabled(0));
+        assert!(!controller.is_slave_output_enabled(1));
+        controller.set_pdo_register(0, target, vec![1]).unwrap();
+        controller.set_pdo_register(1, target, vec![2]).unwrap();
+        controller.step_cycle().unwrap();
+        assert_eq!(controller.get_output_pdo_register(0, target), Some(vec![1]));
+        assert_eq!(controller.get_output_pdo_register(1, target), Some(vec![0]));
+
+        // The writes queued while disabled are discarded.
+        controller.set_slave_output_enabled(1, true);
+        controller.step_cycle().unwrap();
+        assert_eq!(controller.get_output_pdo_register(1, target), Some(vec![0]));
+        controller.set_pdo_register(1, target, vec![3]).unwrap();
+        controller.step_cycle().unwrap();
+        assert_eq!(controller.get_output_pdo_register(1, target), Some(vec![3]));
+    }
+
+    #[test]
+    fn absent_slaves_are_reported() {
+        let bus = MockBus::new(4);
+        bus.slave_states.lock().unwrap().insert(1, None);
+        let controller = mock_controller(builder().manual_cycle(true), bus, two_slaves());
+        let status = "status";
+        assert!(!controller.is_slave_present(0));
+
+        controller.step_cycle().unwrap();
+        assert!(controller.is_slave_present(0));
+        assert_eq!(controller.slave_state(0), Some(AlState::Op));
+        assert_eq!(controller.read_pdo_register(0, status, 0).unwrap(), [0]);
+        assert!(!controller.is_slave_present(1));
+        assert_eq!(controller.slave_state(1), None);
+        let err = controller.read_pdo_register(1, status, 0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+        let err = controller.read_pdo_register(0, "unknown", 0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn configured_epos_params_are_retained() {
+        let config: Config = serde_yaml::from_str(
+            "
+ethercat:
+  master_id: 0
+  esi: unused.xml
+slaves:
+  - !Epos
+    id: 0
+    encoder_resolution: 4096
+    reduction: 53.0
+  - !Epos
+    id: 2
+    encoder_resolution: 1024
+    reduction: 1.0
+    rated_torque: 0.5
+",
+        )
+        .unwrap();
+        let controller = mock_controller(
+            EtherCatControllerBuilder::from_config(&config, Duration::from_millis(1)),
+            MockBus::new(4),
+            output_and_input(),
+        );
+
+        let epos = controller.epos_params(0).unwrap();
+        assert_eq!(epos.encoder_resolution, 4096);
+        assert_eq!(epos.reduction, 53.0);
+        assert_eq!(epos.rated_torque, 0.0);
+
+        let epos = controller.epos_params(2).unwrap();
+        assert_eq!(epos.encoder_resolution, 1024);
+        assert_eq!(epos.rated_torque, 0.5);
+        assert!(matches!(
+            controller.slave_config(2),
+            Some(SlaveConfig::Epos(EposKind { id: 2, .. }))
+        ));
+
+        assert!(controller.epos_params(1).is_none());
+        assert!(controller.slave_config(1).is_none());
+    }
+
+    #[test]
+    fn wait_until_returns_once_the_predicate_holds() {
+        let controller = mock_controller(
+            builder().manual_cycle(true),
+            MockBus::new(4),
+            output_and_input(),
+        );
+        let target = "target";
+        let stop = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                for value in 0u8.. {
+                    if stop.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    controller
+                        .set_pdo_register(0, target, vec![value, 0])
+                        .unwrap();
+                    controller.step_cycle().unwrap();
+                    thread::sleep(Duration::from_millis(1));
+                }
+            });
+
+            let result =
+                controller.wait_until(0, target, 0, |value| value[0] >= 3, Duration::from_secs(1));
+            stop.store(true, Ordering::Relaxed);
+            result.unwrap();
+        });
+        assert!(controller.get_pdo_register(0, target).unwrap()[0] >= 3);
+    }
+
+    #[test]
+    fn wait_until_times_out() {
+        let controller = mock_controller(
+            builder().manual_cycle(true),
+            MockBus::new(4),
+            output_and_input(),
+        );
+        controller.step_cycle().unwrap();
+
+        let start = Instant::now();
+        let err = controller
+            .wait_until(0, "target", 0, |_| false, Duration::from_millis(20))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn writes_to_unknown_registers_fail() {
+        let bus = MockBus::new(4);
+        let sent = Arc::clone(&bus.sent);
+        let controller = mock_controller(builder().manual_cycle(true), bus, output_and_input());
+
+        for (slave_id, register, index) in [(0, "unknown", 0), (0, "target", 1), (1, "target", 0)] {
+            let err = controller
+                .set_pdo_register_at(slave_id, register, index, vec![1, 2])
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        }
+
+        controller.step_cycle().unwrap();
+        assert_eq!(sent.lock().unwrap()[0], [0; 4]);
+    }
+
+    #[test]
+    fn unknown_registers_read_as_none() {
+        let controller = mock_controller(
+            builder().manual_cycle(true),
+            MockBus::new(4),
+            output_and_input(),
+        );
+        controller.step_cycle().unwrap();
+        controller.step_cycle().unwrap();
+
+        assert_eq!(controller.get_pdo_register(0, "unknown"), None);
+        assert_eq!(controller.get_pdo_register_at(0, "target", 1), None);
+        assert_eq!(controller.get_output_pdo_register(1, "target"), None);
+        assert_eq!(controller.read_with_previous(0, "unknown", 0), None);
+        assert!(controller.read_with_previous(0, "target", 0).is_some());
+    }
+
+    #[test]
+    fn wait_until_fails_straight_away_on_unknown_registers() {
+        let controller = mock_controller(
+            builder().manual_cycle(true),
+            MockBus::new(4),
+            output_and_input(),
+        );
+
+        let err = controller
+            .wait_until(0, "unknown", 0, |_| true, Duration::from_secs(60))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn input_writes_are_rejected_unless_allowed() {
+        let input = layout(0x6000, 16, 0, Direction::Input);
+        let output = layout(0x7000, 16, 2, Direction::Output);
+        let target = || "\"status\"[0] of slave 0".to_string();
+
+        let err = check_writable("test", false, &input, target).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(err.to_string().contains("\"status\"[0] of slave 0"));
+
+        assert!(check_writable("test", true, &input, target).is_ok());
+        assert!(check_writable("test", false, &output, target).is_ok());
+    }
+
+    #[test]
+    fn output_write_checks_the_register() {
+        let offsets = output_and_input();
+
+        let write = output_write(&offsets, 0, "status", 0, &[1, 2], "safe value").unwrap();
+        assert_eq!(write.range, 2..4);
+        assert_eq!(write.value, [1, 2]);
+
+        for (slave_id, register, index, value) in [
+            (0, "unknown", 0, vec![1, 2]),
+            (1, "target", 0, vec![1, 2]),
+            (0, "target", 1, vec![1, 2]),
+            (0, "target", 0, vec![1]),
+        ] {
+            let err = output_write(&offsets, slave_id, register, index, &value, "safe value")
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn invalid_safe_values_are_rejected_when_opening() {
+        let safe_values = vec![(0, "target".to_string(), 0, vec![1])];
+        let builder = builder().shutdown_outputs(ShutdownBehavior::SafeValue(safe_values));
+
+        let err = EtherCatController::start(
+            builder,
+            Box::new(MockBus::new(4)),
+            output_and_input(),
+            4,
+            HashMap::new(),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn same_named_input_and_output_are_addressed_separately() {
+        let mut offsets = OffsetMap::new();
+        offsets.insert(
+            0,
+            "value".to_string(),
+            layout(0x7000, 16, 0, Direction::Output),
+        );
+        offsets.insert(
+            0,
+            "value".to_string(),
+            layout(0x6000, 16, 2, Direction::Input),
+        );
+        let mut bus = MockBus::new(4);
+        bus.image = vec![0, 0, 7, 8];
+        let controller = mock_controller(builder().manual_cycle(true), bus, offsets);
+        let value = "value";
+
+        controller.set_pdo_register(0, value, vec![1, 2]).unwrap();
+        controller.step_cycle().unwrap();
+        controller.step_cycle().unwrap();
+
+        assert_eq!(
+            controller.get_pdo_register_at(0, value, 0),
+            Some(vec![1, 2])
+        );
+        assert_eq!(
+            controller.get_pdo_register_at(0, value, 1),
+            Some(vec![7, 8])
+        );
+        assert_eq!(
+            controller.get_pdo_register_dir(0, value, 0, Direction::Output),
+            Some(vec![1, 2])
+        );
+        assert_eq!(
+            controller.get_pdo_register_dir(0, value, 0, Direction::Input),
+            Some(vec![7, 8])
+        );
+        assert_eq!(
+            controller.get_pdo_register_dir(0, value, 1, Direction::Input),
+            None
+        );
+    }
+
+    #[test]
+    fn try_get_returns_the_last_received_value() {
+        let controller = mock_controller(
+            builder().manual_cycle(true),
+            MockBus::new(4),
+            output_and_input(),
+        );
+        assert!(controller.try_get_pdo_register(0, "target", 0).is_none());
+
+        controller
+            .set_pdo_register(0, "target", vec![1, 2])
+            .unwrap();
+        controller.step_cycle().unwrap();
+        controller.step_cycle().unwrap();
+
+        assert_eq!(
+            controller.try_get_pdo_register(0, "target", 0),
+            Some(vec![1, 2])
+        );
+    }
+
+    #[test]
+    fn failing_to_reserve_the_master_is_an_error() {
+        assert!(reservation::<String>("test", 0, Ok(())).is_ok());
+
+        let err = reservation("test", 2, Err("Device or resource busy")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        assert!(err.to_string().starts_with("Unable to reserve master 2"));
+    }
+
+    #[test]
+    fn reservation_is_released_on_shutdown() {
+        let mut controller = mock_controller(
+            builder().manual_cycle(true),
+            MockBus::new(4),
+            output_and_input(),
+        );
+        assert!(controller.is_reserved());
+
+        controller.stop();
+        assert!(!controller.is_reserved());
+    }
+
+    #[test]
+    fn panic_is_reported_despite_a_poisoned_lock() {
+        let mut bus = MockBus::new(4);
+        bus.panic_after_sends = Some(3);
+        let mut controller = mock_controller(builder().manual_cycle(true), bus, output_and_input());
+        let task = controller.manual_task.take().unwrap().into_inner().unwrap();
+
+        // Poison the lock the error is recorded in.
+        let poisoned = Arc::clone(&controller.last_error);
+        let _ = thread::spawn(move || {
+            let _guard = poisoned.lock().unwrap();
+            panic!("poisoning the lock");
+        })
+        .join();
+        assert!(controller.last_error.is_poisoned());
+
+        let handle = spawn_cycle_thread(
+            task,
+            0,
+            Duration::from_millis(1),
+            Arc::default(),
+            Arc::clone(&controller.last_error),
+        )
+        .unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(
+            controller.last_error().unwrap(),
+            "Cyclic task panicked: Bus failure"
+        );
+        assert!(!controller.is_reserved());
+        // The last published inputs stay readable.
+        assert!(controller.get_pdo_register(0, "status").is_some());
+    }
+
+    #[test]
+    fn overrun_reports_the_cycle_that_overran() {
+        let mut bus = MockBus::new(4);
+        bus.delay = Duration::from_millis(5);
+        let mut controller = mock_controller(builder().manual_cycle(true), bus, output_and_input());
+        let task = controller.manual_task.take().unwrap().into_inner().unwrap();
+        let events = controller.subscribe_events();
+        let stop_flag = Arc::new(AtomicBool::new(false));
+
+        let handle = spawn_cycle_thread(
+            task,
+            0,
+            Duration::from_millis(1),
+            Arc::clone(&stop_flag),
+            Arc::default(),
+        )
+        .unwrap();
+        let first = events.recv_timeout(Duration::from_secs(1)).unwrap();
+        let second = events.recv_timeout(Duration::from_secs(1)).unwrap();
+        stop_flag.store(true, Ordering::Relaxed);
+        handle.join().unwrap();
+
+        for (expected, event) in [(0, first), (1, second)] {
+            match event {
+                BusEvent::CycleOverrun { cycle, overrun } => {
+                    assert_eq!(cycle, expected);
+                    assert!(overrun >= Duration::from_millis(3));
+                }
+                event => panic!("Unexpected event {:?}", event),
+            }
+        }
+        assert!(controller.timing_stats().overruns >= 2);
+    }
+
+    #[test]
+    fn controllers_of_different_masters_are_independent() {
+        let open = |master_id| {
+            mock_controller(
+                EtherCatControllerBuilder::new("unused.xml", master_id, Duration::from_millis(1))
+                    .manual_cycle(true),
+                MockBus::new(4),
+                output_and_input(),
+            )
+        };
+        let mut first = open(0);
+        let second = open(1);
+
+        first.set_pdo_register(0, "target", vec![1, 1]).unwrap();
+        second.set_pdo_register(0, "target", vec![2, 2]).unwrap();
+        for _ in 0..2 {
+            first.step_cycle().unwrap();
+            second.step_cycle().unwrap();
+        }
+
+        assert_eq!(first.get_pdo_register(0, "target"), Some(vec![1, 1]));
+        assert_eq!(second.get_pdo_register(0, "target"), Some(vec![2, 2]));
+
+        // Only the cycles of the stopped controller stop.
+        first.stop();
+        second.step_cycle().unwrap();
+        assert!(!first.is_reserved());
+        assert!(second.is_reserved());
+        assert_eq!(second.get_pdo_register(0, "target"), Some(vec![2, 2]));
+    }
+
+    /// Slave 0 with three 8-bit outputs named "lword", at bytes 0 to 2.
+    fn three_entries() -> OffsetMap {
+        let mut offsets = OffsetMap::new();
+        for byte in 0..3 {
+            offsets.insert(
+                0,
+                "lword".to_string(),
+                layout(0x7000 + byte as u16, 8, byte, Direction::Output),
+            );
+        }
+        offsets
+    }
+
+    /// Output image after writing `values` to "lword", or the error kind.
+    fn write_lword(lenient: bool, values: Vec<Vec<u8>>) -> Result<Vec<u8>, io::ErrorKind> {
+        let bus = MockBus::new(3);
+        let sent = Arc::clone(&bus.sent);
+        let controller = mock_controller(
+            builder().manual_cycle(true).lenient_writes(lenient),
+            bus,
+            three_entries(),
+        );
+
+        let result = controller.set_pdo_registers(0, "lword", values);
+        controller.step_cycle().unwrap();
+        let image = sent.lock().unwrap()[0].clone();
+        result.map(|()| image).map_err(|err| err.kind())
+    }
+
+    #[test]
+    fn multi_index_writes_need_one_value_per_entry() {
+        let values = |count: u8| (1..=count).map(|value| vec![value]).collect();
+
+        assert_eq!(write_lword(false, values(3)), Ok(vec![1, 2, 3]));
+        assert_eq!(
+            write_lword(false, values(2)),
+            Err(io::ErrorKind::InvalidInput)
+        );
+        assert_eq!(
+            write_lword(false, values(4)),
+            Err(io::ErrorKind::InvalidInput)
+        );
+
+        assert_eq!(write_lword(true, values(3)), Ok(vec![1, 2, 3]));
+        assert_eq!(write_lword(true, values(2)), Ok(vec![1, 2, 0]));
+        assert_eq!(write_lword(true, values(4)), Ok(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn previous_value_is_from_the_cycle_before() {
+        let controller = mock_controller(
+            builder().manual_cycle(true),
+            MockBus::new(4),
+            output_and_input(),
+        );
+        let read = || controller.read_with_previous(0, "target", 0);
+
+        controller.step_cycle().unwrap();
+        assert_eq!(read(), None);
+
+        controller
+            .set_pdo_register(0, "target", vec![1, 2])
+            .unwrap();
+        controller.step_cycle().unwrap();
+        assert_eq!(read(), Some((vec![0, 0], vec![0, 0])));
+
+        // The echoed write comes back in the next cycle.
+        controller.step_cycle().unwrap();
+        assert_eq!(read(), Some((vec![1, 2], vec![0, 0])));
+
+        controller.step_cycle().unwrap();
+        assert_eq!(read(), Some((vec![1, 2], vec![1, 2])));
+    }
+
+    #[test]
+    fn data_ages_until_the_next_cycle() {
+        let controller = mock_controller(
+            builder().manual_cycle(true),
+            MockBus::new(4),
+            output_and_input(),
+        );
+        let max_age = Duration::from_millis(20);
+        assert_eq!(controller.data_age(), None);
+        assert!(controller.is_data_stale(max_age));
+
+        controller.step_cycle().unwrap();
+        let age = controller.data_age().unwrap();
+        assert!(!controller.is_data_stale(max_age));
+
+        thread::sleep(max_age);
+        assert!(controller.data_age().unwrap() > age);
+        assert!(controller.is_data_stale(max_age));
+
+        controller.step_cycle().unwrap();
+        assert!(!controller.is_data_stale(max_age));
+    }
+
+    #[test]
+    fn wait_ready_returns_when_already_ready() {
+        let ready_condvar = Arc::new((Mutex::new(true), Condvar::new()));
+        let (tx, rx) = mpsc::channel();
+
+        let condvar = Arc::clone(&ready_condvar);
+        thread::spawn(move || {
+            wait_ready(&condvar);
+            tx.send(()).unwrap();
+        });
+
+        assert!(rx.recv_timeout(Duration::from_secs(1)).is_ok());
+        assert!(*ready_condvar.0.lock().unwrap());
+    }
+
+    #[test]
+    fn wait_ready_wakes_up_once_ready() {
+        let ready_condvar = Arc::new((Mutex::new(false), Condvar::new()));
+        let (tx, rx) = mpsc::channel();
+
+        let condvar = Arc::clone(&ready_condvar);
+        thread::spawn(move || {
+            wait_ready(&condvar);
+            tx.send(()).unwrap();
+        });
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+
+        *ready_condvar.0.lock().unwrap() = true;
+        ready_condvar.1.notify_all();
+        assert!(rx.recv_timeout(Duration::from_secs(1)).is_ok());
+    }
+
+    #[test]
+    fn op_timeout_names_the_slaves_not_in_op() {
+        let not_in_op = slaves_not_in_op(
+            [(0, Some(AlState::Op)), (1, Some(AlState::PreOp)), (2, None)].into_iter(),
+        );
+        assert_eq!(not_in_op, ["1 (PreOp)", "2 (absent)"]);
+
+        let message = op_timeout_message(0, Duration::from_secs(2), true, &not_in_op);
+        assert_eq!(message, "Slaves not in OP after 2s: 1 (PreOp), 2 (absent)");
+    }
+
+    #[test]
+    fn op_timeout_reports_a_link_down() {
+        let message = op_timeout_message(3, Duration::from_secs(2), false, &[]);
+        assert_eq!(message, "Link of master 3 not up after 2s");
+    }
+
+    #[test]
+    fn opening_waits_for_every_slave_in_op() {
+        let controller = mock_controller(
+            builder().ready_timeout(Duration::from_secs(1)),
+            MockBus::new(4),
+            two_slaves(),
+        );
+        assert_eq!(controller.slave_state(1), Some(AlState::Op));
+
+        let bus = MockBus::new(4);
+        bus.slave_states
+            .lock()
+            .unwrap()
+            .insert(1, Some(AlState::PreOp));
+        let err = EtherCatController::start(
+            builder().ready_timeout(Duration::from_millis(50)),
+            Box::new(bus),
+            two_slaves(),
+            4,
+            HashMap::new(),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(err.to_string(), "Slaves not in OP after 50ms: 1 (PreOp)");
+    }
+
+    #[test]
+    fn entries_are_addressed_by_object_index() {
+        let mut offsets = OffsetMap::new();
+        offsets.insert(0, String::new(), layout(0x7000, 8, 0, Direction::Output));
+        offsets.insert(0, String::new(), layout(0x7001, 8, 1, Direction::Output));
+        let controller = mock_controller(builder().manual_cycle(true), MockBus::new(2), offsets);
+        let idx = |idx| PdoEntryIdx::new(idx, 0);
+
+        controller
+            .set_pdo_register_by_idx(0, idx(0x7001), 0, vec![2])
+            .unwrap();
+        controller
+            .set_pdo_register_by_idx(0, idx(0x7000), 0, vec![1])
+            .unwrap();
+        controller.step_cycle().unwrap();
+        controller.step_cycle().unwrap();
+
+        assert_eq!(
+            controller.get_pdo_register_by_idx(0, idx(0x7000), 0),
+            Some(vec![1])
+        );
+        assert_eq!(
+            controller.get_pdo_register_by_idx(0, idx(0x7001), 0),
+            Some(vec![2])
+        );
+        assert_eq!(controller.get_pdo_register_by_idx(0, idx(0x7001), 1), None);
+        let err = controller
+            .set_pdo_register_by_idx(0, idx(0x7002), 0, vec![3])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn all_objects_are_read_by_index() {
+        let mut offsets = output_and_input();
+        offsets.insert(
+            0,
+            "status".to_string(),
+            layout(0x6000, 8, 4, Direction::Input),
+        );
+        offsets.insert(
+            1,
+            "other".to_string(),
+            layout(0x6000, 8, 5, Direction::Input),
+        );
+        let mut bus = MockBus::new(6);
+        bus.image = vec![1, 2, 3, 4, 5, 6];
+        let controller = mock_controller(builder().manual_cycle(true), bus, offsets);
+        assert_eq!(controller.read_all_by_idx(0), None);
+
+        controller.step_cycle().unwrap();
+        let idx = |idx| PdoEntryIdx::new(idx, 0);
+
+        // The object mapped twice is read from its first entry.
+        assert_eq!(
+            controller.read_all_by_idx(0),
+            Some(HashMap::from([
+                (idx(0x7000), vec![1, 2]),
+                (idx(0x6000), vec![3, 4]),
+            ]))
+        );
+        assert_eq!(
+            controller.read_all_by_idx(1),
+            Some(HashMap::from([(idx(0x6000), vec![6])]))
+        );
+        assert_eq!(controller.read_all_by_idx(2), None);
+    }
+
+    #[test]
+    fn unnamed_entries_are_registered_apart() {
+        let entry = |idx: u16, sub_idx: u8, name: &str| PdoEntryInfo {
+            entry_idx: PdoEntryIdx::new(idx, sub_idx),
+            bit_len: 8,
+            name: name.to_string(),
+            pos: PdoEntryPos::from(0),
+        };
+        let mut offsets = OffsetMap::new();
+        for (byte, entry) in [
+            entry(0x6000, 1, ""),
+            entry(0x6000, 2, ""),
+            entry(0x6010, 1, ""),
+            entry(0x6020, 0, "status"),
+        ]
+        .iter()
+        .enumerate()
+        {
+            let name = register_name("test", &offsets, 0, entry, Direction::Input);
+            offsets.insert(
+                0,
+                name,
+                layout(u16::from(entry.entry_idx.idx), 8, byte, Direction::Input),
+            );
+        }
+
+        assert_eq!(
+            offsets.registers(0),
+            ["6000:01", "6000:02", "6010:01", "status"]
+        );
+        for register in offsets.registers(0) {
+            assert_eq!(offsets.entries(0, register).len(), 1);
+        }
+        assert_eq!(offsets.entry(0, "6000:02", 0).unwrap().byte_offset, 1);
+    }
+
+    #[test]
+    fn expected_contribution_follows_the_slave_state() {
+        let mut offsets = OffsetMap::new();
+        offsets.insert(
+            0,
+            "target".to_string(),
+            layout(0x7000, 16, 2, Direction::Output),
+        );
+        offsets.insert(
+            0,
+            "status".to_string(),
+            layout(0x6000, 16, 0, Direction::Input),
+        );
+        offsets.insert(
+            1,
+            "status".to_string(),
+            layout(0x6000, 16, 4, Direction::Input),
+        );
+
+        assert_eq!(expected_contribution(&offsets, 0, Some(AlState::Op)), 3);
+        assert_eq!(expected_contribution(&offsets, 0, Some(AlState::SafeOp)), 1);
+        assert_eq!(expected_contribution(&offsets, 0, Some(AlState::PreOp)), 0);
+        assert_eq!(expected_contribution(&offsets, 0, None), 0);
+        assert_eq!(expected_contribution(&offsets, 1, Some(AlState::Op)), 1);
+    }
+
+    #[test]
+    fn domain_working_counter_is_known_after_the_first_cycle() {
+        let controller = mock_controller(
+            builder().manual_cycle(true),
+            MockBus::new(4),
+            output_and_input(),
+        );
+        assert_eq!(controller.domain_working_counter(), None);
+        assert_eq!(controller.expected_domain_working_counter(), 3);
+
+        controller.step_cycle().unwrap();
+        assert_eq!(controller.domain_working_counter(), Some(3));
+        assert_eq!(controller.expected_slave_contribution(0), Some(3));
+        assert_eq!(controller.expected_slave_contribution(1), None);
+    }
+
+    #[test]
+    fn a_slave_dropping_out_lowers_the_domain_working_counter_only() {
+        let bus = MockBus::new(4);
+        let working_counter = Arc::clone(&bus.working_counter);
+        let controller = mock_controller(builder().manual_cycle(true), bus, output_and_input());
+
+        // The slave stops writing its inputs while still reported in OP: the
+        // domain counter shows it, the expectations do not change.
+        working_counter.store(2, Ordering::Relaxed);
+        controller.step_cycle().unwrap();
+        assert_eq!(controller.domain_working_counter(), Some(2));
+        assert_eq!(controller.expected_domain_working_counter(), 3);
+        assert_eq!(controller.expected_slave_contribution(0), Some(3));
+    }
+
+    #[test]
+    fn cyclic_threads_are_named_after_their_master() {
+        let thread_name = |master_id| {
+            let controller = mock_controller(
+                EtherCatControllerBuilder::new("unused.xml", master_id, Duration::from_millis(1)),
+                MockBus::new(4),
+                output_and_input(),
+            );
+            let handle = controller.cycle_thread.as_ref().unwrap();
+            handle.thread().name().unwrap().to_string()
+        };
+
+        assert_eq!(thread_name(0), "ethercat-master-0");
+        assert_eq!(thread_name(1), "ethercat-master-1");
+    }
+
+    /// Device with two selectable PDOs in each direction, of one entry each.
+    fn device() -> Device {
+        let pdo = |idx: u16, sm: u8, entry: u16| Pdo {
+            sm: sm.into(),
+            fixed: false,
+            mandatory: false,
+            idx: idx.into(),
+            name: None,
+            entries: vec![ethercat_esi::PdoEntry {
+                entry_idx: PdoEntryIdx::new(entry, 0),
+                bit_len: 16,
+                name: Some(format!("{:X}", entry)),
+                data_type: None,
+            }],
+        };
+
+        Device {
+            physics: None,
+            name: "drive".to_string(),
+            desc: String::new(),
+            product_code: 0,
+            revision_no: 0,
+            sm: Vec::new(),
+            rx_pdo: vec![pdo(0x1600, 2, 0x6040), pdo(0x1601, 2, 0x60FF)],
+            tx_pdo: vec![pdo(0x1A00, 3, 0x6041), pdo(0x1A01, 3, 0x606C)],
+        }
+    }
+
+    fn pdo_indices(pdos: &[PdoCfg]) -> Vec<u16> {
+        pdos.iter().map(|pdo| u16::from(pdo.idx)).collect()
+    }
+
+    /// ESI of vendor 0x1234 describing the devices.
+    fn esi(devices: Vec<Device>) -> EtherCatInfo {
+        EtherCatInfo {
+            version: None,
+            info_reference: None,
+            vendor: ethercat_esi::Vendor {
+                file_version: None,
+                id: 0x1234,
+                name: Some("vendor".to_string()),
+                comment: None,
+                url: None,
+                desc_url: None,
+                image: None,
+            },
+            description: ethercat_esi::Description {
+                devices,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn in_memory_esi_is_used_as_is() {
+        let esi = esi(vec![device()]);
+        let ethercat_config = EthercatConfig {
+            master_id: 3,
+            esi: "ignored.xml".to_string(),
+            ..Default::default()
+        };
+        let builder = EtherCatControllerBuilder::from_esi(esi, 0, Duration::from_millis(1))
+            .ethercat_config(&ethercat_config);
+        assert_eq!(builder.master_id, 3);
+
+        // Kept by the copy reopening the controller on a rescan.
+        let esi = load_esi(builder.settings().esi).unwrap();
+        assert_eq!(esi.vendor.id, 0x1234);
+        assert_eq!(esi.description.devices.len(), 1);
+        assert_eq!(esi.description.devices[0].rx_pdo.len(), 2);
+
+        let err = load_esi(EsiSource::File("missing.xml".to_string())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn dry_run_plan_lists_the_sync_managers_and_entries() {
+        let mut offsets = OffsetMap::new();
+        offsets.insert(
+            0,
+            "6040".to_string(),
+            layout(0x6040, 16, 0, Direction::Output),
+        );
+        offsets.insert(
+            0,
+            "60FF".to_string(),
+            layout(0x60FF, 16, 2, Direction::Output),
+        );
+        offsets.insert(
+            1,
+            "60FF".to_string(),
+            layout(0x60FF, 16, 4, Direction::Output),
+        );
+        let assignments = [PdoAssignment {
+            slave_id: 1,
+            sync_manager: 2,
+            pdos: vec![0x1601],
+        }];
+
+        let plan =
+            configuration_plan(0, &esi(vec![device(), device()]), &assignments, offsets).unwrap();
+        assert_eq!(
+            plan.sync_managers[2],
+            PlannedSyncManager {
+                slave_id: 1,
+                sync_manager: 2,
+                pdos: vec![0x1601],
+            }
+        );
+        assert_eq!(
+            plan.to_string(),
+            "Master 0: 2 slaves, 3 entries, 6 bytes domain
+Slave 0:
+  SM2: [0x1600, 0x1601]
+  SM3: [0x1A00, 0x1A01]
+  \"6040\"[0] 6040:0 Output, byte 0 bit 0, 16 bits
+  \"60FF\"[0] 60FF:0 Output, byte 2 bit 0, 16 bits
+Slave 1:
+  SM2: [0x1601]
+  SM3: [0x1A00, 0x1A01]
+  \"60FF\"[0] 60FF:0 Output, byte 4 bit 0, 16 bits
+"
+        );
+    }
+
+    #[test]
+    fn dry_run_configures_the_slaves_without_activating_the_master() {
+        let mut bus = MockBus::new(4);
+        bus.offsets = output_and_input();
+        let activated = Arc::clone(&bus.activated);
+
+        let plan = dry_run_on(&mut bus, &builder(), &esi(vec![device()])).unwrap();
+        assert_eq!(plan.offsets.total_entry_count(), 2);
+        assert_eq!(plan.offsets.expected_domain_size(), 4);
+        assert!(!activated.load(Ordering::Relaxed));
+
+        // The configured slaves must still be on the bus.
+        let mut builder = builder();
+        builder
+            .slaves
+            .insert(1, SlaveConfig::Epos(EposKind::default()));
+        let err = dry_run_on(&mut bus, &builder, &esi(vec![device()])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(!activated.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn configured_pdo_assignment_replaces_the_esi_one() {
+        let assignments = [PdoAssignment {
+            slave_id: 1,
+            sync_manager: 2,
+            pdos: vec![0x1601],
+        }];
+
+        let (rx_pdos, tx_pdos) = sm_pdos(&device(), &assignments, 1).unwrap();
+        assert_eq!(pdo_indices(&rx_pdos), [0x1601]);
+        assert_eq!(rx_pdos[0].entries[0].entry_idx, PdoEntryIdx::new(0x60FF, 0));
+        assert_eq!(pdo_indices(&tx_pdos), [0x1A00, 0x1A01]);
+
+        // The assignment of another slave is left out.
+        let (rx_pdos, _) = sm_pdos(&device(), &assignments, 0).unwrap();
+        assert_eq!(pdo_indices(&rx_pdos), [0x1600, 0x1601]);
+    }
+
+    #[test]
+    fn invalid_pdo_assignments_are_rejected() {
+        let assign = |sync_manager, pdos| {
+            sm_pdos(
+                &device(),
+                &[PdoAssignment {
+                    slave_id: 0,
+                    sync_manager,
+                    pdos,
+                }],
+                0,
+            )
+            .unwrap_err()
+            .kind()
+        };
+
+        assert_eq!(assign(3, vec![0x1A02]), io::ErrorKind::NotFound);
+        assert_eq!(assign(2, vec![0x1A00]), io::ErrorKind::NotFound);
+        assert_eq!(assign(1, vec![0x1600]), io::ErrorKind::InvalidInput);
+    }
 }
@@ -0,0 +1,51 @@
+use std::{
+    ops::Range,
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Mutex, PoisonError,
+    },
+    time::Duration,
+};
+
+/// Notable things happening on the bus, reported by the cyclic task.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BusEvent {
+    /// The cycle work took longer than the period, so the next cycle starts
+    /// late by `overrun`.
+    CycleOverrun { cycle: u64, overrun: Duration },
+    /// A write was skipped because it does not fit in the domain image, or
+    /// its value does not match the register width.
+    InvalidWrite {
+        slave_id: u16,
+        range: Range<usize>,
+        value_len: usize,
+        domain_len: usize,
+    },
+}
+
+/// Fans the events out to every subscriber, without ever blocking the
+/// cyclic task.
+#[derive(Debug, Default)]
+pub(crate) struct EventBus {
+    subscribers: Mutex<Vec<Sender<BusEvent>>>,
+}
+
+impl EventBus {
+    pub(crate) fn subscribe(&self) -> Receiver<BusEvent> {
+        let (tx, rx) = channel();
+        self.subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(tx);
+        rx
+    }
+
+    pub(crate) fn emit(&self, event: BusEvent) {
+        let mut subscribers = self
+            .subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        // Dropped receivers are forgotten.
+        subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
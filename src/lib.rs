@@ -1,5 +1,39 @@
 pub mod config;
 pub use config::Config;
 
+mod builder;
+pub use builder::{EtherCatControllerBuilder, ShutdownBehavior};
+
 mod ethercat_controller;
 pub use ethercat_controller::EtherCatController;
+
+mod offsets;
+pub use offsets::{Direction, EntryLayout, LayoutChange, OffsetMap};
+
+mod plan;
+pub use plan::{ConfigurationPlan, PlannedSyncManager};
+
+mod scan;
+pub use scan::{scan_bus, ScannedPdo, ScannedSlave, ScannedSyncManager};
+
+mod sdo;
+pub use sdo::PreOp;
+
+mod snapshot;
+
+mod cycle;
+
+mod events;
+pub use events::BusEvent;
+
+mod logging;
+pub use logging::DataLogging;
+
+mod metrics;
+pub use metrics::{MasterMetrics, MetricsSnapshot, TimingStats};
+
+mod values;
+pub use values::{DataType, TypedValue, TypedWrite};
+
+mod epos;
+pub use epos::FaultInfo;
@@ -0,0 +1,282 @@
+use std::ops::Range;
+
+use crate::offsets::OffsetMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataLogging {
+    Off,
+    /// Logs the domain buffer as a byte list every `every` cycles.
+    Raw {
+        every: u32,
+    },
+    /// Logs the domain buffer as an hex string every `every` cycles.
+    Hex {
+        every: u32,
+    },
+    /// Logs only the registers whose value changed since the previous cycle.
+    Changes,
+}
+
+impl Default for DataLogging {
+    fn default() -> Self {
+        DataLogging::Raw { every: 1 }
+    }
+}
+
+/// Log target of a controller, under the crate one so that the crate wide
+/// filters still apply, e.g. `ethercat_controller::master0::arm`.
+pub(crate) fn log_target(master_id: u32, label: Option<&str>) -> String {
+    match label {
+        Some(label) => format!(
+            "{}::master{}::{}",
+            env!("CARGO_CRATE_NAME"),
+            master_id,
+            label
+        ),
+        None => format!("{}::master{}", env!("CARGO_CRATE_NAME"), master_id),
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct DataLogger {
+    log_target: String,
+    mode: DataLogging,
+    registers: Vec<(u16, String, usize, Range<usize>)>,
+    previous: Vec<u8>,
+}
+
+impl DataLogger {
+    pub(crate) fn new(log_target: String, mode: DataLogging, offsets: &OffsetMap) -> Self {
+        let mut registers = Vec::new();
+
+        if mode == DataLogging::Changes {
+            for (slave_id, name, index, layout) in offsets.iter() {
+                registers.push((slave_id, name.to_string(), index, layout.byte_range()));
+            }
+            registers.sort_by_key(|(slave_id, _, _, range)| (*slave_id, range.start));
+        }
+
+        DataLogger {
+            log_target,
+            mode,
+            registers,
+            previous: Vec::new(),
+        }
+    }
+
+    pub(crate) fn log(&mut self, cycle: u64, data: &[u8]) {
+        if !log::log_enabled!(target: &self.log_target, log::Level::Debug) {
+            return;
+        }
+
+        match self.mode {
+            DataLogging::Off => {}
+            DataLogging::Raw { every } => {
+                if cycle.is_multiple_of(u64::from(every.max(1))) {
+                    log::debug!(target: &self.log_target, "{:?}", data);
+                }
+            }
+            DataLogging::Hex { every } => {
+                if cycle.is_multiple_of(u64::from(every.max(1))) {
+                    log::debug!(
+                        target: &self.log_target,
+                        "{}",
+                        data.iter()
+                            .map(|b| format!("{:02X}", b))
+                            .collect::<String>()
+                    );
+                }
+            }
+            DataLogging::Changes => self.log_changes(data),
+        }
+    }
+
+    fn log_changes(&mut self, data: &[u8]) {
+        if self.previous.len() != data.len() {
+            self.previous = data.to_vec();
+            return;
+        }
+        if self.previous == data {
+            return;
+        }
+
+        for (slave_id, name, index, range) in &self.registers {
+            if range.end > data.len() || self.previous[range.clone()] == data[range.clone()] {
+                continue;
+            }
+            log::debug!(
+                target: &self.log_target,
+                "Slave {} \"{}\"[{}]: {:?} -> {:?}",
+                slave_id,
+                name,
+                index,
+                &self.previous[range.clone()],
+                &data[range.clone()]
+            );
+        }
+
+        self.previous.copy_from_slice(data);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::Mutex;
+
+    use log::{Log, Metadata, Record};
+
+    use super::*;
+    use crate::{
+        cycle::tests::MockBus,
+        ethercat_controller::tests::{builder, mock_controller},
+        offsets::{tests::layout, Direction},
+    };
+
+    /// Keeps the target and message of every record, except for the targets
+    /// ending with `::filtered` which are disabled.
+    pub(crate) struct CaptureLogger {
+        records: Mutex<Vec<(String, String)>>,
+    }
+
+    impl CaptureLogger {
+        pub(crate) fn install() -> &'static Self {
+            let _ = log::set_logger(&LOGGER);
+            log::set_max_level(log::LevelFilter::Trace);
+            &LOGGER
+        }
+
+        /// Messages logged under the target, which each test keeps unique
+        /// since they log concurrently.
+        pub(crate) fn messages(&self, target: &str) -> Vec<String> {
+            self.records
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(record_target, _)| record_target == target)
+                .map(|(_, message)| message.clone())
+                .collect()
+        }
+    }
+
+    impl Log for CaptureLogger {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            !metadata.target().ends_with("::filtered")
+        }
+
+        fn log(&self, record: &Record) {
+            self.records
+                .lock()
+                .unwrap()
+                .push((record.target().to_string(), record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CaptureLogger = CaptureLogger {
+        records: Mutex::new(Vec::new()),
+    };
+
+    #[test]
+    fn log_target_has_the_master_id_and_label() {
+        assert_eq!(log_target(3, None), "ethercat_controller::master3");
+        assert_eq!(
+            log_target(3, Some("arm")),
+            "ethercat_controller::master3::arm"
+        );
+    }
+
+    #[test]
+    fn cyclic_task_logs_under_the_controller_target() {
+        let logger = CaptureLogger::install();
+
+        let controller = mock_controller(
+            builder().manual_cycle(true).label("capture"),
+            MockBus::new(2),
+            OffsetMap::new(),
+        );
+        assert_eq!(
+            controller.log_target(),
+            "ethercat_controller::master0::capture"
+        );
+        controller.step_cycle().unwrap();
+
+        assert!(logger
+            .messages("ethercat_controller::master0::capture")
+            .iter()
+            .any(|message| message == "Master ready!"));
+    }
+
+    #[test]
+    fn data_is_only_logged_when_enabled() {
+        let logger = CaptureLogger::install();
+
+        // Filtered out: nothing is formatted nor even compared.
+        let mut data_logger = DataLogger::new(
+            "data::filtered".to_string(),
+            DataLogging::Changes,
+            &OffsetMap::new(),
+        );
+        data_logger.log(0, &[1, 2]);
+        data_logger.log(1, &[3, 4]);
+        assert!(data_logger.previous.is_empty());
+
+        let mut data_logger =
+            DataLogger::new("data::off".to_string(), DataLogging::Off, &OffsetMap::new());
+        data_logger.log(0, &[1, 2]);
+        assert!(logger.messages("data::off").is_empty());
+
+        let mut data_logger = DataLogger::new(
+            "data::raw".to_string(),
+            DataLogging::Raw { every: 2 },
+            &OffsetMap::new(),
+        );
+        let mut data_logger_hex = DataLogger::new(
+            "data::hex".to_string(),
+            DataLogging::Hex { every: 1 },
+            &OffsetMap::new(),
+        );
+        for cycle in 0..3 {
+            data_logger.log(cycle, &[1, 2]);
+            data_logger_hex.log(cycle, &[0xAB, 0x01]);
+        }
+        assert_eq!(logger.messages("data::raw"), ["[1, 2]", "[1, 2]"]);
+        assert_eq!(logger.messages("data::hex"), ["AB01", "AB01", "AB01"]);
+    }
+
+    #[test]
+    fn only_the_changed_registers_are_logged() {
+        let logger = CaptureLogger::install();
+
+        let mut offsets = OffsetMap::new();
+        offsets.insert(
+            0,
+            "status".to_string(),
+            layout(0x6041, 16, 0, Direction::Input),
+        );
+        offsets.insert(
+            0,
+            "position".to_string(),
+            layout(0x6064, 32, 2, Direction::Input),
+        );
+        offsets.insert(
+            1,
+            "status".to_string(),
+            layout(0x6041, 16, 6, Direction::Input),
+        );
+        let mut data_logger =
+            DataLogger::new("data::changes".to_string(), DataLogging::Changes, &offsets);
+
+        let mut data = [0; 8];
+        data_logger.log(0, &data);
+        data_logger.log(1, &data);
+        data[3] = 1;
+        data_logger.log(2, &data);
+        data_logger.log(3, &data);
+
+        assert_eq!(
+            logger.messages("data::changes"),
+            ["Slave 0 \"position\"[0]: [0, 0, 0, 0] -> [0, 1, 0, 0]"]
+        );
+    }
+}
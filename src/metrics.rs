@@ -0,0 +1,342 @@
+use std::{
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct MasterMetrics {
+    /// Number of successful send calls, one per cycle.
+    pub frames_sent: u64,
+    /// Number of successful receive calls, one per cycle.
+    pub frames_received: u64,
+    pub receive_errors: u64,
+    pub process_errors: u64,
+    pub send_errors: u64,
+    /// Number of writes skipped because they did not fit in the domain.
+    pub invalid_writes: u64,
+    pub reserved: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct TimingStats {
+    /// Number of cycles whose work took longer than the cycle period.
+    pub overruns: u64,
+    pub max_overrun: Duration,
+}
+
+/// Everything worth monitoring about a controller, in one place.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct MetricsSnapshot {
+    pub master: MasterMetrics,
+    pub timing: TimingStats,
+    pub domain_working_counter: Option<u16>,
+    pub expected_domain_working_counter: u16,
+    pub slaves_present: usize,
+    pub slaves_total: usize,
+}
+
+#[cfg(feature = "metrics")]
+impl MetricsSnapshot {
+    /// Publishes the snapshot through the `metrics` facade, labelled with the
+    /// master id, to be picked up by whichever recorder is installed.
+    pub fn record(&self, master_id: u32) {
+        let labels = [("master", master_id.to_string())];
+
+        ::metrics::counter!("ethercat_frames_sent_total", &labels)
+            .absolute(self.master.frames_sent);
+        ::metrics::counter!("ethercat_frames_received_total", &labels)
+            .absolute(self.master.frames_received);
+        ::metrics::counter!("ethercat_receive_errors_total", &labels)
+            .absolute(self.master.receive_errors);
+        ::metrics::counter!("ethercat_process_errors_total", &labels)
+            .absolute(self.master.process_errors);
+        ::metrics::counter!("ethercat_send_errors_total", &labels)
+            .absolute(self.master.send_errors);
+        ::metrics::counter!("ethercat_invalid_writes_total", &labels)
+            .absolute(self.master.invalid_writes);
+        ::metrics::gauge!("ethercat_reserved", &labels)
+            .set(f64::from(u8::from(self.master.reserved)));
+        ::metrics::counter!("ethercat_cycle_overruns_total", &labels)
+            .absolute(self.timing.overruns);
+        ::metrics::gauge!("ethercat_cycle_max_overrun_seconds", &labels)
+            .set(self.timing.max_overrun.as_secs_f64());
+        if let Some(wkc) = self.domain_working_counter {
+            ::metrics::gauge!("ethercat_domain_working_counter", &labels).set(f64::from(wkc));
+        }
+        ::metrics::gauge!("ethercat_domain_expected_working_counter", &labels)
+            .set(f64::from(self.expected_domain_working_counter));
+        ::metrics::gauge!("ethercat_slaves_present", &labels).set(self.slaves_present as f64);
+        ::metrics::gauge!("ethercat_slaves_total", &labels).set(self.slaves_total as f64);
+    }
+}
+
+/// Counts of the cycle work durations, by bucket. A duration falls in the
+/// first bucket whose upper edge it does not exceed, or in the last one,
+/// unbounded.
+#[derive(Debug)]
+pub(crate) struct CycleHistogram {
+    edges: Vec<Duration>,
+    counts: Vec<AtomicU64>,
+}
+
+impl CycleHistogram {
+    pub(crate) fn new(mut edges: Vec<Duration>) -> Self {
+        edges.sort();
+        edges.dedup();
+        let counts = (0..=edges.len()).map(|_| AtomicU64::new(0)).collect();
+        CycleHistogram { edges, counts }
+    }
+
+    /// Default buckets, relative to the cycle period.
+    pub(crate) fn for_period(cycle_period: Duration) -> Self {
+        Self::new(vec![
+            cycle_period / 4,
+            cycle_period / 2,
+            cycle_period * 3 / 4,
+            cycle_period,
+            cycle_period * 2,
+        ])
+    }
+
+    pub(crate) fn record(&self, duration: Duration) {
+        let bucket = self.edges.partition_point(|edge| *edge < duration);
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `(upper edge, count)` of each bucket, the last edge being
+    /// `Duration::MAX`.
+    pub(crate) fn snapshot(&self) -> Vec<(Duration, u64)> {
+        self.edges
+            .iter()
+            .copied()
+            .chain([Duration::MAX])
+            .zip(&self.counts)
+            .map(|(edge, count)| (edge, count.load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct MasterCounters {
+    pub(crate) frames_sent: AtomicU64,
+    pub(crate) frames_received: AtomicU64,
+    pub(crate) receive_errors: AtomicU64,
+    pub(crate) process_errors: AtomicU64,
+    pub(crate) send_errors: AtomicU64,
+    pub(crate) invalid_writes: AtomicU64,
+    pub(crate) reserved: AtomicBool,
+    pub(crate) domain_working_counter: AtomicU32,
+    pub(crate) domain_state_known: AtomicBool,
+    pub(crate) write_batches_queued: AtomicU64,
+    pub(crate) write_batches_applied: AtomicU64,
+    pub(crate) overruns: AtomicU64,
+    pub(crate) max_overrun_ns: AtomicU64,
+}
+
+impl MasterCounters {
+    pub(crate) fn snapshot(&self) -> MasterMetrics {
+        MasterMetrics {
+            frames_sent: self.frames_sent.load(Ordering::Relaxed),
+            frames_received: self.frames_received.load(Ordering::Relaxed),
+            receive_errors: self.receive_errors.load(Ordering::Relaxed),
+            process_errors: self.process_errors.load(Ordering::Relaxed),
+            send_errors: self.send_errors.load(Ordering::Relaxed),
+            invalid_writes: self.invalid_writes.load(Ordering::Relaxed),
+            reserved: self.reserved.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn timing_stats(&self) -> TimingStats {
+        TimingStats {
+            overruns: self.overruns.load(Ordering::Relaxed),
+            max_overrun: Duration::from_nanos(self.max_overrun_ns.load(Ordering::Relaxed)),
+        }
+    }
+
+    pub(crate) fn count_overrun(&self, overrun: Duration) {
+        self.overruns.fetch_add(1, Ordering::Relaxed);
+        self.max_overrun_ns
+            .fetch_max(overrun.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn domain_working_counter(&self) -> Option<u16> {
+        self.domain_state_known
+            .load(Ordering::Relaxed)
+            .then(|| self.domain_working_counter.load(Ordering::Relaxed) as u16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        cycle::tests::MockBus,
+        ethercat_controller::tests::{builder, mock_controller, output_and_input},
+    };
+
+    #[test]
+    fn master_metrics_count_the_cycles_and_errors() {
+        let bus = MockBus::new(4);
+        let receive_fails = Arc::clone(&bus.receive_fails);
+        let mut controller = mock_controller(builder().manual_cycle(true), bus, output_and_input());
+
+        for _ in 0..3 {
+            controller.step_cycle().unwrap();
+        }
+        receive_fails.store(true, Ordering::Relaxed);
+        for _ in 0..2 {
+            controller.step_cycle().unwrap();
+        }
+
+        assert_eq!(
+            controller.master_metrics(),
+            MasterMetrics {
+                frames_sent: 5,
+                frames_received: 3,
+                receive_errors: 2,
+                process_errors: 0,
+                send_errors: 0,
+                invalid_writes: 0,
+                reserved: true,
+            }
+        );
+
+        controller.stop();
+        assert!(!controller.master_metrics().reserved);
+    }
+
+    #[test]
+    fn cycle_durations_fall_in_their_bucket() {
+        let ms = Duration::from_millis;
+        // Unsorted and duplicated edges are normalized.
+        let histogram = CycleHistogram::new(vec![ms(2), ms(1), ms(2)]);
+
+        for duration in [
+            ms(0),
+            ms(1),
+            ms(1),
+            ms(2) - Duration::from_nanos(1),
+            ms(2),
+            ms(5),
+        ] {
+            histogram.record(duration);
+        }
+
+        assert_eq!(
+            histogram.snapshot(),
+            [(ms(1), 3), (ms(2), 2), (Duration::MAX, 1)]
+        );
+    }
+
+    #[test]
+    fn default_buckets_follow_the_period() {
+        let ms = Duration::from_millis;
+        let histogram = CycleHistogram::for_period(ms(4));
+        let edges: Vec<Duration> = histogram.snapshot().iter().map(|(edge, _)| *edge).collect();
+
+        assert_eq!(edges, [ms(1), ms(2), ms(3), ms(4), ms(8), Duration::MAX]);
+    }
+
+    #[test]
+    fn metrics_snapshot_reflects_the_cycles() {
+        let controller = mock_controller(
+            builder().manual_cycle(true),
+            MockBus::new(4),
+            output_and_input(),
+        );
+        for _ in 0..2 {
+            controller.step_cycle().unwrap();
+        }
+
+        assert_eq!(
+            controller.metrics_snapshot(),
+            MetricsSnapshot {
+                master: MasterMetrics {
+                    frames_sent: 2,
+                    frames_received: 2,
+                    reserved: true,
+                    ..MasterMetrics::default()
+                },
+                timing: TimingStats::default(),
+                domain_working_counter: Some(3),
+                expected_domain_working_counter: 3,
+                slaves_present: 1,
+                slaves_total: 1,
+            }
+        );
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn snapshot_is_recorded_under_the_expected_names() {
+        use std::sync::Mutex;
+
+        use ::metrics::{
+            Counter, Gauge, Histogram, Key, KeyName, Metadata, Recorder, SharedString, Unit,
+        };
+
+        /// Keeps the names and labels of the registered metrics.
+        #[derive(Default)]
+        struct NameRecorder(Mutex<Vec<String>>);
+
+        impl NameRecorder {
+            fn register(&self, key: &Key) {
+                let labels: Vec<String> = key
+                    .labels()
+                    .map(|label| format!("{}={}", label.key(), label.value()))
+                    .collect();
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("{}{{{}}}", key.name(), labels.join(",")));
+            }
+        }
+
+        impl Recorder for NameRecorder {
+            fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+            fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+            fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+            fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+                self.register(key);
+                Counter::noop()
+            }
+
+            fn register_gauge(&self, key: &Key, _: &Metadata<'_>) -> Gauge {
+                self.register(key);
+                Gauge::noop()
+            }
+
+            fn register_histogram(&self, key: &Key, _: &Metadata<'_>) -> Histogram {
+                self.register(key);
+                Histogram::noop()
+            }
+        }
+
+        let recorder = NameRecorder::default();
+        ::metrics::with_local_recorder(&recorder, || MetricsSnapshot::default().record(2));
+        let names = recorder.0.into_inner().unwrap();
+
+        assert_eq!(
+            names,
+            [
+                "ethercat_frames_sent_total{master=2}",
+                "ethercat_frames_received_total{master=2}",
+                "ethercat_receive_errors_total{master=2}",
+                "ethercat_process_errors_total{master=2}",
+                "ethercat_send_errors_total{master=2}",
+                "ethercat_invalid_writes_total{master=2}",
+                "ethercat_reserved{master=2}",
+                "ethercat_cycle_overruns_total{master=2}",
+                "ethercat_cycle_max_overrun_seconds{master=2}",
+                "ethercat_domain_expected_working_counter{master=2}",
+                "ethercat_slaves_present{master=2}",
+                "ethercat_slaves_total{master=2}",
+            ]
+        );
+    }
+}
@@ -0,0 +1,475 @@
+use std::{collections::HashMap, ops::Range};
+
+use ethercat::{Offset, PdoEntryIdx};
+
+use crate::values::DataType;
+
+/// Direction of a PDO entry, seen from the master.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// Written by the slave (TxPDO).
+    Input,
+    /// Written by the master (RxPDO).
+    Output,
+}
+
+/// Where a PDO entry lives in the domain image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryLayout {
+    pub pdo_entry_idx: PdoEntryIdx,
+    pub bit_len: u8,
+    pub byte_offset: usize,
+    pub bit_offset: u32,
+    pub direction: Direction,
+    pub data_type: DataType,
+}
+
+impl EntryLayout {
+    pub fn new(
+        pdo_entry_idx: PdoEntryIdx,
+        bit_len: u8,
+        offset: Offset,
+        direction: Direction,
+    ) -> Self {
+        EntryLayout {
+            pdo_entry_idx,
+            bit_len,
+            byte_offset: offset.byte,
+            bit_offset: offset.bit,
+            direction,
+            data_type: DataType::Unknown,
+        }
+    }
+
+    pub fn with_data_type(mut self, data_type: DataType) -> Self {
+        self.data_type = data_type;
+        self
+    }
+
+    /// Number of bytes touched by the entry in the domain image.
+    pub fn byte_width(&self) -> usize {
+        (self.bit_len as usize).div_ceil(8)
+    }
+
+    pub fn byte_range(&self) -> Range<usize> {
+        self.byte_offset..self.byte_offset + self.byte_width()
+    }
+
+    /// Whether both entries are mapped identically, whatever their data type.
+    pub fn same_layout(&self, other: &EntryLayout) -> bool {
+        self.pdo_entry_idx == other.pdo_entry_idx
+            && self.bit_len == other.bit_len
+            && self.byte_offset == other.byte_offset
+            && self.bit_offset == other.bit_offset
+            && self.direction == other.direction
+    }
+}
+
+/// Difference in a register entry between two offset maps.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LayoutChange {
+    Added {
+        slave_id: u16,
+        register: String,
+        index: usize,
+    },
+    Removed {
+        slave_id: u16,
+        register: String,
+        index: usize,
+    },
+    /// Same register, but its direction, PDO entry, width or offset changed.
+    Changed {
+        slave_id: u16,
+        register: String,
+        index: usize,
+        previous: EntryLayout,
+        current: EntryLayout,
+    },
+}
+
+/// Registers of each slave, by name. Entries sharing the same name are all
+/// kept, in registration order.
+#[derive(Debug, Clone, Default)]
+pub struct OffsetMap {
+    slaves: HashMap<u16, HashMap<String, Vec<EntryLayout>>>,
+    by_idx: HashMap<u16, HashMap<PdoEntryIdx, Vec<EntryLayout>>>,
+}
+
+impl OffsetMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends an entry to the register, after the ones already known under
+    /// the same name.
+    pub fn insert(&mut self, slave_id: u16, register: String, layout: EntryLayout) {
+        self.by_idx
+            .entry(slave_id)
+            .or_default()
+            .entry(layout.pdo_entry_idx)
+            .or_default()
+            .push(layout);
+        self.slaves
+            .entry(slave_id)
+            .or_default()
+            .entry(register)
+            .or_default()
+            .push(layout);
+    }
+
+    /// Sets the data type of all the entries registered under the name,
+    /// returning whether there was any.
+    pub fn set_data_type(&mut self, slave_id: u16, register: &str, data_type: DataType) -> bool {
+        let Some(layouts) = self
+            .slaves
+            .get_mut(&slave_id)
+            .and_then(|registers| registers.get_mut(register))
+        else {
+            return false;
+        };
+
+        for layout in layouts.iter_mut() {
+            layout.data_type = data_type;
+            for other in self
+                .by_idx
+                .get_mut(&slave_id)
+                .and_then(|entries| entries.get_mut(&layout.pdo_entry_idx))
+                .into_iter()
+                .flatten()
+                .filter(|other| {
+                    other.byte_offset == layout.byte_offset && other.bit_offset == layout.bit_offset
+                })
+            {
+                other.data_type = data_type;
+            }
+        }
+        true
+    }
+
+    /// Declares a slave, even if it has no register.
+    pub fn add_slave(&mut self, slave_id: u16) {
+        self.slaves.entry(slave_id).or_default();
+    }
+
+    pub fn slaves(&self) -> Vec<u16> {
+        let mut ids: Vec<u16> = self.slaves.keys().copied().collect();
+        ids.sort();
+        ids
+    }
+
+    pub fn contains_slave(&self, slave_id: u16) -> bool {
+        self.slaves.contains_key(&slave_id)
+    }
+
+    /// Register names of the slave, sorted.
+    pub fn registers(&self, slave_id: u16) -> Vec<&str> {
+        let mut names: Vec<&str> = self
+            .slaves
+            .get(&slave_id)
+            .map(|registers| registers.keys().map(String::as_str).collect())
+            .unwrap_or_default();
+        names.sort();
+        names
+    }
+
+    /// Number of register names of the slave.
+    pub fn register_count(&self, slave_id: u16) -> usize {
+        self.slaves.get(&slave_id).map_or(0, HashMap::len)
+    }
+
+    /// Number of entries of the slave, counting each index of a register.
+    pub fn entry_count(&self, slave_id: u16) -> usize {
+        self.slaves
+            .get(&slave_id)
+            .map_or(0, |registers| registers.values().map(Vec::len).sum())
+    }
+
+    pub fn total_register_count(&self) -> usize {
+        self.slaves.values().map(HashMap::len).sum()
+    }
+
+    pub fn total_entry_count(&self) -> usize {
+        self.slaves
+            .values()
+            .flat_map(HashMap::values)
+            .map(Vec::len)
+            .sum()
+    }
+
+    /// All the entries registered under the given name.
+    pub fn entries(&self, slave_id: u16, register: &str) -> &[EntryLayout] {
+        self.slaves
+            .get(&slave_id)
+            .and_then(|registers| registers.get(register))
+            .map_or(&[], Vec::as_slice)
+    }
+
+    pub fn entry(&self, slave_id: u16, register: &str, index: usize) -> Option<EntryLayout> {
+        self.entries(slave_id, register).get(index).copied()
+    }
+
+    /// Working counter increment expected from the slave for the domain
+    /// datagram: 2 if it has outputs, plus 1 if it has inputs.
+    pub fn expected_working_counter(&self, slave_id: u16) -> u8 {
+        self.working_counter_of(slave_id, true, true)
+    }
+
+    pub(crate) fn working_counter_of(&self, slave_id: u16, outputs: bool, inputs: bool) -> u8 {
+        let has = |direction: Direction| {
+            self.slaves.get(&slave_id).is_some_and(|registers| {
+                registers
+                    .values()
+                    .flatten()
+                    .any(|layout| layout.direction == direction)
+            })
+        };
+
+        let mut wkc = 0;
+        if outputs && has(Direction::Output) {
+            wkc += 2;
+        }
+        if inputs && has(Direction::Input) {
+            wkc += 1;
+        }
+        wkc
+    }
+
+    /// Smallest domain image holding every registered entry.
+    pub fn expected_domain_size(&self) -> usize {
+        self.iter()
+            .map(|(_, _, _, layout)| layout.byte_range().end)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Object dictionary indices mapped by the slave.
+    pub fn objects(&self, slave_id: u16) -> Vec<PdoEntryIdx> {
+        self.by_idx
+            .get(&slave_id)
+            .map(|entries| entries.keys().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Entry addressed by its object dictionary index, independently of its
+    /// name. `occurrence` selects among the entries mapping the same object,
+    /// in registration order.
+    pub fn entry_by_idx(
+        &self,
+        slave_id: u16,
+        pdo_entry_idx: PdoEntryIdx,
+        occurrence: usize,
+    ) -> Option<EntryLayout> {
+        self.by_idx
+            .get(&slave_id)
+            .and_then(|entries| entries.get(&pdo_entry_idx))
+            .and_then(|layouts| layouts.get(occurrence))
+            .copied()
+    }
+
+    /// Iterates over `(slave_id, register, index, layout)`, in no particular
+    /// order.
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &str, usize, &EntryLayout)> {
+        self.slaves.iter().flat_map(|(slave_id, registers)| {
+            registers.iter().flat_map(move |(name, layouts)| {
+                layouts
+                    .iter()
+                    .enumerate()
+                    .map(move |(index, layout)| (*slave_id, name.as_str(), index, layout))
+            })
+        })
+    }
+
+    /// Lists the entries added, removed or moved since `previous`, sorted by
+    /// slave, register and index. Data types are not compared.
+    pub fn compare(&self, previous: &OffsetMap) -> Vec<LayoutChange> {
+        let mut changes = Vec::new();
+
+        let mut slave_ids = self.slaves();
+        slave_ids.extend(previous.slaves());
+        slave_ids.sort();
+        slave_ids.dedup();
+
+        for slave_id in slave_ids {
+            let mut registers = self.registers(slave_id);
+            registers.extend(previous.registers(slave_id));
+            registers.sort();
+            registers.dedup();
+
+            for register in registers {
+                let current = self.entries(slave_id, register);
+                let before = previous.entries(slave_id, register);
+
+                for index in 0..current.len().max(before.len()) {
+                    let register = register.to_string();
+                    match (before.get(index), current.get(index)) {
+                        (None, Some(_)) => changes.push(LayoutChange::Added {
+                            slave_id,
+                            register,
+                            index,
+                        }),
+                        (Some(_), None) => changes.push(LayoutChange::Removed {
+                            slave_id,
+                            register,
+                            index,
+                        }),
+                        (Some(previous), Some(current)) if !previous.same_layout(current) => {
+                            changes.push(LayoutChange::Changed {
+                                slave_id,
+                                register,
+                                index,
+                                previous: *previous,
+                                current: *current,
+                            })
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        changes
+    }
+
+    /// Bytes of the domain image covered by the entry, `None` if unknown.
+    pub(crate) fn byte_range(
+        &self,
+        slave_id: u16,
+        register: &str,
+        index: usize,
+    ) -> Option<Range<usize>> {
+        self.entry(slave_id, register, index)
+            .map(|layout| layout.byte_range())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Byte aligned entry, sub-index 0 of the object.
+    pub(crate) fn layout(idx: u16, bit_len: u8, byte: usize, direction: Direction) -> EntryLayout {
+        EntryLayout::new(
+            PdoEntryIdx::new(idx, 0),
+            bit_len,
+            Offset { byte, bit: 0 },
+            direction,
+        )
+    }
+
+    #[test]
+    fn map_is_queried_by_slave_register_and_index() {
+        let mut offsets = OffsetMap::new();
+        offsets.insert(
+            1,
+            "status".to_string(),
+            layout(0x6041, 16, 4, Direction::Input),
+        );
+        offsets.insert(
+            0,
+            "lword".to_string(),
+            layout(0x7000, 32, 0, Direction::Output),
+        );
+        offsets.insert(
+            0,
+            "lword".to_string(),
+            layout(0x7001, 32, 8, Direction::Output),
+        );
+        offsets.add_slave(2);
+
+        assert_eq!(offsets.slaves(), [0, 1, 2]);
+        assert!(offsets.contains_slave(2));
+        assert!(!offsets.contains_slave(3));
+        assert_eq!(offsets.registers(0), ["lword"]);
+        assert!(offsets.registers(2).is_empty());
+        assert_eq!(offsets.entries(0, "lword").len(), 2);
+        assert_eq!(offsets.iter().count(), 3);
+
+        let entry = offsets.entry(0, "lword", 1).unwrap();
+        assert_eq!(u16::from(entry.pdo_entry_idx.idx), 0x7001);
+        assert_eq!(entry.byte_offset, 8);
+        assert_eq!(entry.byte_width(), 4);
+        assert_eq!(entry.byte_range(), 8..12);
+        assert_eq!(offsets.entry(0, "lword", 2), None);
+        assert_eq!(offsets.entry(1, "lword", 0), None);
+    }
+
+    #[test]
+    fn compare_reports_added_removed_and_changed_entries() {
+        let mut previous = OffsetMap::new();
+        previous.insert(
+            0,
+            "target".to_string(),
+            layout(0x607A, 16, 0, Direction::Output),
+        );
+        previous.insert(
+            0,
+            "status".to_string(),
+            layout(0x6041, 16, 2, Direction::Input),
+        );
+        previous.insert(1, "old".to_string(), layout(0x6000, 8, 4, Direction::Input));
+
+        let mut current = OffsetMap::new();
+        current.insert(
+            0,
+            "target".to_string(),
+            layout(0x607A, 32, 0, Direction::Output),
+        );
+        current.insert(
+            0,
+            "status".to_string(),
+            layout(0x6041, 16, 4, Direction::Input),
+        );
+        current.insert(
+            0,
+            "status".to_string(),
+            layout(0x6042, 16, 6, Direction::Input),
+        );
+
+        assert!(current.compare(&current).is_empty());
+        let mut retyped = current.clone();
+        assert!(retyped.set_data_type(0, "target", DataType::I32));
+        assert!(retyped.compare(&current).is_empty());
+        assert_eq!(
+            current.compare(&previous),
+            [
+                LayoutChange::Changed {
+                    slave_id: 0,
+                    register: "status".to_string(),
+                    index: 0,
+                    previous: layout(0x6041, 16, 2, Direction::Input),
+                    current: layout(0x6041, 16, 4, Direction::Input),
+                },
+                LayoutChange::Added {
+                    slave_id: 0,
+                    register: "status".to_string(),
+                    index: 1,
+                },
+                LayoutChange::Changed {
+                    slave_id: 0,
+                    register: "target".to_string(),
+                    index: 0,
+                    previous: layout(0x607A, 16, 0, Direction::Output),
+                    current: layout(0x607A, 32, 0, Direction::Output),
+                },
+                LayoutChange::Removed {
+                    slave_id: 1,
+                    register: "old".to_string(),
+                    index: 0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn byte_width_rounds_partial_bytes_up() {
+        let entry = EntryLayout::new(
+            PdoEntryIdx::new(0x6000, 1),
+            12,
+            Offset { byte: 3, bit: 4 },
+            Direction::Input,
+        );
+        assert_eq!(entry.byte_width(), 2);
+        assert_eq!(entry.byte_range(), 3..5);
+    }
+}
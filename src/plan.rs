@@ -0,0 +1,65 @@
+use std::fmt;
+
+use crate::offsets::OffsetMap;
+
+/// PDOs assigned to a sync manager of a slave.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedSyncManager {
+    pub slave_id: u16,
+    pub sync_manager: u8,
+    pub pdos: Vec<u16>,
+}
+
+/// What opening the controller would configure, as produced by
+/// `EtherCatControllerBuilder::dry_run`.
+#[derive(Debug, Clone)]
+pub struct ConfigurationPlan {
+    pub master_id: u32,
+    pub sync_managers: Vec<PlannedSyncManager>,
+    pub offsets: OffsetMap,
+}
+
+impl fmt::Display for ConfigurationPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Master {}: {} slaves, {} entries, {} bytes domain",
+            self.master_id,
+            self.offsets.slaves().len(),
+            self.offsets.total_entry_count(),
+            self.offsets.expected_domain_size()
+        )?;
+
+        for slave_id in self.offsets.slaves() {
+            writeln!(f, "Slave {}:", slave_id)?;
+
+            for sm in self
+                .sync_managers
+                .iter()
+                .filter(|sm| sm.slave_id == slave_id)
+            {
+                let pdos: Vec<String> = sm.pdos.iter().map(|idx| format!("{:#06X}", idx)).collect();
+                writeln!(f, "  SM{}: [{}]", sm.sync_manager, pdos.join(", "))?;
+            }
+
+            for register in self.offsets.registers(slave_id) {
+                for (index, layout) in self.offsets.entries(slave_id, register).iter().enumerate() {
+                    writeln!(
+                        f,
+                        "  \"{}\"[{}] {:X}:{:X} {:?}, byte {} bit {}, {} bits",
+                        register,
+                        index,
+                        u16::from(layout.pdo_entry_idx.idx),
+                        u8::from(layout.pdo_entry_idx.sub_idx),
+                        layout.direction,
+                        layout.byte_offset,
+                        layout.bit_offset,
+                        layout.bit_len
+                    )?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,261 @@
+use std::io;
+
+use ethercat::{
+    AlState, Master, MasterAccess, PdoInfo, PdoPos, SlaveInfo, SlavePos, SmIdx, SmInfo,
+};
+
+use crate::logging::log_target;
+
+#[derive(Debug, Clone)]
+pub struct ScannedSlave {
+    pub position: u16,
+    pub name: String,
+    pub vendor_id: u32,
+    pub product_code: u32,
+    pub revision: u32,
+    pub serial_number: u32,
+    pub al_state: AlState,
+    pub sync_managers: Vec<ScannedSyncManager>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ScannedSyncManager {
+    pub index: u8,
+    pub start_address: u16,
+    pub enabled: bool,
+    pub pdos: Vec<ScannedPdo>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ScannedPdo {
+    pub index: u16,
+    pub name: String,
+    pub entry_count: u8,
+}
+
+/// Lists the slaves attached to a master from their SII, without reserving
+/// nor configuring the bus.
+pub fn scan_bus(master_id: u32) -> Result<Vec<ScannedSlave>, io::Error> {
+    let mut master = Master::open(master_id, MasterAccess::ReadOnly)?;
+
+    scan(&log_target(master_id, None), &mut master)
+}
+
+/// Read-only queries of the scan, answered by the master.
+trait SlaveInfoSource {
+    fn slave_count(&mut self) -> Result<u32, io::Error>;
+    fn slave_info(&mut self, slave_pos: SlavePos) -> Result<SlaveInfo, io::Error>;
+    fn sync(&mut self, slave_pos: SlavePos, sm: SmIdx) -> Result<SmInfo, io::Error>;
+    fn pdo(&mut self, slave_pos: SlavePos, sm: SmIdx, pdo: PdoPos) -> Result<PdoInfo, io::Error>;
+}
+
+impl SlaveInfoSource for Master {
+    fn slave_count(&mut self) -> Result<u32, io::Error> {
+        Ok(self.get_info()?.slave_count)
+    }
+
+    fn slave_info(&mut self, slave_pos: SlavePos) -> Result<SlaveInfo, io::Error> {
+        Ok(self.get_slave_info(slave_pos)?)
+    }
+
+    fn sync(&mut self, slave_pos: SlavePos, sm: SmIdx) -> Result<SmInfo, io::Error> {
+        Ok(self.get_sync(slave_pos, sm)?)
+    }
+
+    fn pdo(&mut self, slave_pos: SlavePos, sm: SmIdx, pdo: PdoPos) -> Result<PdoInfo, io::Error> {
+        Ok(self.get_pdo(slave_pos, sm, pdo)?)
+    }
+}
+
+fn scan(
+    log_target: &str,
+    master: &mut impl SlaveInfoSource,
+) -> Result<Vec<ScannedSlave>, io::Error> {
+    let slave_count = master.slave_count()?;
+
+    let mut slaves = Vec::new();
+
+    for position in 0..slave_count as u16 {
+        let slave_pos = SlavePos::from(position);
+        let slave_info = master.slave_info(slave_pos)?;
+        log::debug!(
+            target: log_target,
+            "Found slave {}: {:?}",
+            position,
+            slave_info
+        );
+
+        let mut sync_managers = Vec::new();
+        for sm in 0..slave_info.sync_count {
+            let sm_info = master.sync(slave_pos, SmIdx::from(sm))?;
+
+            let mut pdos = Vec::new();
+            for pdo in 0..sm_info.pdo_count {
+                let pdo_info = master.pdo(slave_pos, SmIdx::from(sm), PdoPos::from(pdo))?;
+                pdos.push(ScannedPdo {
+                    index: u16::from(pdo_info.idx),
+                    name: pdo_info.name,
+                    entry_count: pdo_info.entry_count,
+                });
+            }
+
+            sync_managers.push(ScannedSyncManager {
+                index: sm,
+                start_address: sm_info.start_addr,
+                enabled: sm_info.enable,
+                pdos,
+            });
+        }
+
+        slaves.push(ScannedSlave {
+            position,
+            name: slave_info.name,
+            vendor_id: slave_info.id.vendor_id,
+            product_code: slave_info.id.product_code,
+            revision: slave_info.rev.revision_number,
+            serial_number: slave_info.rev.serial_number,
+            al_state: slave_info.al_state,
+            sync_managers,
+        });
+    }
+
+    Ok(slaves)
+}
+
+#[cfg(test)]
+mod tests {
+    use ethercat::{Idx, SlaveId, SlaveRev};
+
+    use super::*;
+
+    type MockSyncManager = (SmInfo, Vec<PdoInfo>);
+
+    /// Bus of slaves with, for each one, its sync managers and their PDOs.
+    struct MockSii {
+        slaves: Vec<(SlaveInfo, Vec<MockSyncManager>)>,
+    }
+
+    impl SlaveInfoSource for MockSii {
+        fn slave_count(&mut self) -> Result<u32, io::Error> {
+            Ok(self.slaves.len() as u32)
+        }
+
+        fn slave_info(&mut self, slave_pos: SlavePos) -> Result<SlaveInfo, io::Error> {
+            Ok(self.slaves[usize::from(u16::from(slave_pos))].0.clone())
+        }
+
+        fn sync(&mut self, slave_pos: SlavePos, sm: SmIdx) -> Result<SmInfo, io::Error> {
+            Ok(self.slaves[usize::from(u16::from(slave_pos))].1[usize::from(u8::from(sm))].0)
+        }
+
+        fn pdo(
+            &mut self,
+            slave_pos: SlavePos,
+            sm: SmIdx,
+            pdo: PdoPos,
+        ) -> Result<PdoInfo, io::Error> {
+            Ok(
+                self.slaves[usize::from(u16::from(slave_pos))].1[usize::from(u8::from(sm))].1
+                    [usize::from(u8::from(pdo))]
+                .clone(),
+            )
+        }
+    }
+
+    fn slave_info(position: u16, name: &str, sync_count: u8) -> SlaveInfo {
+        SlaveInfo {
+            name: name.to_string(),
+            ring_pos: position,
+            id: SlaveId {
+                vendor_id: 0xFB,
+                product_code: 0x6315_0000 + u32::from(position),
+            },
+            rev: SlaveRev {
+                revision_number: 0x0100_0000,
+                serial_number: 1000 + u32::from(position),
+            },
+            alias: 0,
+            current_on_ebus: 0,
+            al_state: AlState::PreOp,
+            error_flag: 0,
+            sync_count,
+            sdo_count: 0,
+            ports: Default::default(),
+        }
+    }
+
+    fn sm_info(sm: u8, start_addr: u16, pdo_count: u8) -> SmInfo {
+        SmInfo {
+            idx: SmIdx::from(sm),
+            start_addr,
+            default_size: 0,
+            control_register: 0,
+            enable: pdo_count > 0,
+            pdo_count,
+        }
+    }
+
+    fn pdo_info(sm: u8, pos: u8, idx: u16, name: &str, entry_count: u8) -> PdoInfo {
+        PdoInfo {
+            sm: SmIdx::from(sm),
+            pos: PdoPos::from(pos),
+            idx: Idx::from(idx),
+            entry_count,
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn scan_lists_the_slaves_with_their_pdos() {
+        let mut sii = MockSii {
+            slaves: vec![
+                (
+                    slave_info(0, "EPOS4", 4),
+                    vec![
+                        (sm_info(0, 0x1000, 0), vec![]),
+                        (sm_info(1, 0x1400, 0), vec![]),
+                        (
+                            sm_info(2, 0x1800, 1),
+                            vec![pdo_info(2, 0, 0x1600, "RxPDO 1", 3)],
+                        ),
+                        (
+                            sm_info(3, 0x1C00, 2),
+                            vec![
+                                pdo_info(3, 0, 0x1A00, "TxPDO 1", 4),
+                                pdo_info(3, 1, 0x1A01, "TxPDO 2", 1),
+                            ],
+                        ),
+                    ],
+                ),
+                (slave_info(1, "IO", 0), vec![]),
+            ],
+        };
+
+        let slaves = scan("test", &mut sii).unwrap();
+
+        assert_eq!(slaves.len(), 2);
+        let epos = &slaves[0];
+        assert_eq!(
+            (
+                epos.position,
+                epos.name.as_str(),
+                epos.vendor_id,
+                epos.product_code
+            ),
+            (0, "EPOS4", 0xFB, 0x6315_0000)
+        );
+        assert_eq!((epos.revision, epos.serial_number), (0x0100_0000, 1000));
+        assert_eq!(epos.al_state, AlState::PreOp);
+        assert_eq!(epos.sync_managers.len(), 4);
+        assert!(!epos.sync_managers[0].enabled);
+        let txpdos = &epos.sync_managers[3];
+        assert_eq!((txpdos.index, txpdos.start_address), (3, 0x1C00));
+        let pdos: Vec<_> = txpdos
+            .pdos
+            .iter()
+            .map(|pdo| (pdo.index, pdo.name.as_str(), pdo.entry_count))
+            .collect();
+        assert_eq!(pdos, [(0x1A00, "TxPDO 1", 4), (0x1A01, "TxPDO 2", 1)]);
+        assert!(slaves[1].sync_managers.is_empty());
+    }
+}
@@ -0,0 +1,215 @@
+use std::{
+    fmt, io,
+    sync::{Mutex, PoisonError},
+};
+
+use ethercat::{Master, SdoIdx, SlavePos};
+
+/// Handle given to the PREOP hook, while the slaves are configured but the
+/// master is not activated yet.
+pub struct PreOp<'a> {
+    pub(crate) master: &'a mut dyn SdoAccess,
+    pub(crate) slave_ids: Vec<u16>,
+}
+
+impl PreOp<'_> {
+    pub fn slave_ids(&self) -> &[u16] {
+        &self.slave_ids
+    }
+
+    pub fn sdo_read(
+        &self,
+        slave_id: u16,
+        index: u16,
+        sub_index: u8,
+        len: usize,
+    ) -> Result<Vec<u8>, io::Error> {
+        self.master.upload(slave_id, index, sub_index, len)
+    }
+
+    pub fn sdo_write(
+        &mut self,
+        slave_id: u16,
+        index: u16,
+        sub_index: u8,
+        value: &[u8],
+    ) -> Result<(), io::Error> {
+        self.master.download(slave_id, index, sub_index, value)
+    }
+}
+
+type PreOpFn = dyn FnOnce(&mut PreOp) -> Result<(), io::Error> + Send;
+
+/// Behind a mutex so that the builder, kept by the controller to reopen the
+/// master, stays `Sync`.
+pub(crate) struct PreOpHook(pub(crate) Mutex<Box<PreOpFn>>);
+
+impl fmt::Debug for PreOpHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PreOpHook")
+    }
+}
+
+/// SDO transfers available to the PREOP hook, answered by the master.
+pub(crate) trait SdoAccess {
+    fn upload(
+        &self,
+        slave_id: u16,
+        index: u16,
+        sub_index: u8,
+        len: usize,
+    ) -> Result<Vec<u8>, io::Error>;
+    fn download(
+        &mut self,
+        slave_id: u16,
+        index: u16,
+        sub_index: u8,
+        value: &[u8],
+    ) -> Result<(), io::Error>;
+}
+
+impl SdoAccess for Master {
+    fn upload(
+        &self,
+        slave_id: u16,
+        index: u16,
+        sub_index: u8,
+        len: usize,
+    ) -> Result<Vec<u8>, io::Error> {
+        sdo_upload(self, slave_id, index, sub_index, len)
+    }
+
+    fn download(
+        &mut self,
+        slave_id: u16,
+        index: u16,
+        sub_index: u8,
+        value: &[u8],
+    ) -> Result<(), io::Error> {
+        sdo_download(self, slave_id, index, sub_index, value)
+    }
+}
+
+/// Runs the PREOP hook, if any, then activates the master. The master is
+/// left inactive if the hook fails.
+pub(crate) fn bring_up<M: SdoAccess>(
+    log_target: &str,
+    master: &mut M,
+    slave_ids: Vec<u16>,
+    on_preop: Option<PreOpHook>,
+    activate: impl FnOnce(&mut M) -> Result<(), io::Error>,
+) -> Result<(), io::Error> {
+    if let Some(on_preop) = on_preop {
+        log::info!(target: log_target, "Running PREOP hook");
+        let hook = on_preop
+            .0
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner);
+        hook(&mut PreOp { master, slave_ids })?;
+    }
+
+    activate(master)
+}
+
+pub(crate) fn sdo_upload(
+    master: &Master,
+    slave_id: u16,
+    index: u16,
+    sub_index: u8,
+    len: usize,
+) -> Result<Vec<u8>, io::Error> {
+    let mut value = vec![0; len];
+    let read = master
+        .sdo_upload(
+            SlavePos::from(slave_id),
+            SdoIdx::new(index, sub_index),
+            false,
+            &mut value,
+        )?
+        .len();
+    value.truncate(read);
+
+    Ok(value)
+}
+
+pub(crate) fn sdo_download(
+    master: &mut Master,
+    slave_id: u16,
+    index: u16,
+    sub_index: u8,
+    value: &[u8],
+) -> Result<(), io::Error> {
+    master.sdo_download(
+        SlavePos::from(slave_id),
+        SdoIdx::new(index, sub_index),
+        false,
+        &value,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records the transfers and the activation, in order.
+    #[derive(Default)]
+    struct MockMaster {
+        events: Vec<String>,
+    }
+
+    impl SdoAccess for MockMaster {
+        fn upload(
+            &self,
+            _slave_id: u16,
+            _index: u16,
+            _sub_index: u8,
+            len: usize,
+        ) -> Result<Vec<u8>, io::Error> {
+            Ok(vec![0; len])
+        }
+
+        fn download(
+            &mut self,
+            slave_id: u16,
+            index: u16,
+            sub_index: u8,
+            _value: &[u8],
+        ) -> Result<(), io::Error> {
+            self.events
+                .push(format!("{}: {:X}:{:X}", slave_id, index, sub_index));
+            Ok(())
+        }
+    }
+
+    fn activate(master: &mut MockMaster) -> Result<(), io::Error> {
+        master.events.push("activate".to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn preop_hook_runs_before_activation() {
+        let mut master = MockMaster::default();
+        let hook = PreOpHook(Mutex::new(Box::new(|preop: &mut PreOp| {
+            for slave_id in preop.slave_ids().to_vec() {
+                preop.sdo_write(slave_id, 0x6060, 0, &[1])?;
+            }
+            Ok(())
+        })));
+
+        bring_up("test", &mut master, vec![0, 1], Some(hook), activate).unwrap();
+        assert_eq!(master.events, ["0: 6060:0", "1: 6060:0", "activate"]);
+    }
+
+    #[test]
+    fn failing_preop_hook_prevents_activation() {
+        let mut master = MockMaster::default();
+        let hook = PreOpHook(Mutex::new(Box::new(|_: &mut PreOp| {
+            Err(io::Error::other("rejected"))
+        })));
+
+        assert!(bring_up("test", &mut master, vec![0], Some(hook), activate).is_err());
+        assert!(master.events.is_empty());
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use arc_swap::ArcSwapOption;
+
+/// Latest domain image published by the cyclic task, along with the one
+/// published the cycle before.
+///
+/// Publishing swaps a single pointer, so readers never block the cyclic task
+/// nor the other way around, and always see an image, the previous one and
+/// the publication time from the same cycle.
+#[derive(Debug, Default)]
+pub(crate) struct Snapshot {
+    published: ArcSwapOption<Published>,
+}
+
+pub(crate) type Image = Arc<Vec<u8>>;
+
+#[derive(Debug)]
+struct Published {
+    current: Image,
+    previous: Option<Image>,
+    published_at: Instant,
+}
+
+impl Snapshot {
+    pub(crate) fn load(&self) -> Option<Image> {
+        self.published
+            .load()
+            .as_ref()
+            .map(|published| Arc::clone(&published.current))
+    }
+
+    /// Current and previous images, `None` until two cycles were published.
+    pub(crate) fn load_with_previous(&self) -> Option<(Image, Image)> {
+        let published = self.published.load();
+        let published = published.as_ref()?;
+        Some((
+            Arc::clone(&published.current),
+            Arc::clone(published.previous.as_ref()?),
+        ))
+    }
+
+    /// Time elapsed since the last publication, `None` if nothing was
+    /// published yet.
+    pub(crate) fn age(&self) -> Option<Duration> {
+        self.published
+            .load()
+            .as_ref()
+            .map(|published| published.published_at.elapsed())
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Publisher {
+    snapshot: Arc<Snapshot>,
+    current: Option<Image>,
+    previous: Option<Image>,
+    spare: Option<Image>,
+}
+
+impl Publisher {
+    pub(crate) fn new(snapshot: Arc<Snapshot>) -> Self {
+        Publisher {
+            snapshot,
+            current: None,
+            previous: None,
+            spare: None,
+        }
+    }
+
+    pub(crate) fn publish(&mut self, data: &[u8]) {
+        // Reuse the buffer published two cycles ago unless a reader still
+        // holds it.
+        let mut next = self
+            .spare
+            .take()
+            .filter(|spare| Arc::strong_count(spare) == 1)
+            .unwrap_or_else(|| Arc::new(Vec::with_capacity(data.len())));
+
+        let buffer = Arc::make_mut(&mut next);
+        buffer.clear();
+        buffer.extend_from_slice(data);
+
+        self.spare = self.previous.take();
+        self.previous = self.current.replace(Arc::clone(&next));
+        self.snapshot.published.store(Some(Arc::new(Published {
+            current: next,
+            previous: self.previous.clone(),
+            published_at: Instant::now(),
+        })));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+    };
+
+    use super::*;
+
+    #[test]
+    fn buffers_are_reused_across_cycles() {
+        let snapshot = Arc::new(Snapshot::default());
+        let mut publisher = Publisher::new(Arc::clone(&snapshot));
+
+        let mut buffers = Vec::new();
+        for cycle in 0..10u8 {
+            publisher.publish(&[cycle; 32]);
+            let image = snapshot.load().unwrap();
+            buffers.push((image.as_ptr(), image.capacity()));
+        }
+
+        // Three buffers rotate: the current, the previous and a spare.
+        assert_eq!(buffers[3..], buffers[..7]);
+        assert_ne!(buffers[0], buffers[1]);
+        assert_ne!(buffers[0], buffers[2]);
+    }
+
+    #[test]
+    fn buffers_held_by_readers_are_not_reused() {
+        let snapshot = Arc::new(Snapshot::default());
+        let mut publisher = Publisher::new(Arc::clone(&snapshot));
+
+        publisher.publish(&[1; 4]);
+        let held = snapshot.load().unwrap();
+        for cycle in 2..6 {
+            publisher.publish(&[cycle; 4]);
+        }
+
+        assert_eq!(held[..], [1; 4]);
+        assert_eq!(snapshot.load().unwrap()[..], [5; 4]);
+    }
+
+    #[test]
+    fn readers_see_consistent_images_while_publishing() {
+        let snapshot = Arc::new(Snapshot::default());
+        let mut publisher = Publisher::new(Arc::clone(&snapshot));
+        let done = Arc::new(AtomicBool::new(false));
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let snapshot = Arc::clone(&snapshot);
+                let done = Arc::clone(&done);
+                thread::spawn(move || {
+                    let mut loads = 0;
+                    while loads == 0 || !done.load(Ordering::Relaxed) {
+                        if let Some((current, previous)) = snapshot.load_with_previous() {
+                            // Every image is filled with its cycle number.
+                            assert!(current.iter().all(|byte| *byte == current[0]));
+                            assert!(previous.iter().all(|byte| *byte == previous[0]));
+                            assert_eq!(current[0], previous[0].wrapping_add(1));
+                            loads += 1;
+                        }
+                    }
+                    loads
+                })
+            })
+            .collect();
+
+        for cycle in 0..20_000u32 {
+            publisher.publish(&[cycle as u8; 64]);
+        }
+        done.store(true, Ordering::Relaxed);
+
+        for reader in readers {
+            assert!(reader.join().unwrap() > 0);
+        }
+        assert_eq!(snapshot.load().unwrap()[0], 19_999u32 as u8);
+        assert!(snapshot.age().is_some());
+    }
+}
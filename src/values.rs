@@ -0,0 +1,687 @@
+use std::{io, ops::Range};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{cycle::PdoWrite, offsets::EntryLayout, EtherCatController};
+
+/// How the bytes of a register are to be interpreted.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum DataType {
+    Bool,
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+    F32,
+    F64,
+    #[default]
+    Unknown,
+}
+
+impl DataType {
+    /// Maps an ESI (IEC 61131-3) data type name, e.g. "DINT".
+    pub fn from_esi_name(name: &str) -> Self {
+        match name {
+            "BOOL" | "BIT" => DataType::Bool,
+            "USINT" | "BYTE" => DataType::U8,
+            "SINT" => DataType::I8,
+            "UINT" | "WORD" => DataType::U16,
+            "INT" => DataType::I16,
+            "UDINT" | "DWORD" => DataType::U32,
+            "DINT" => DataType::I32,
+            "ULINT" | "LWORD" => DataType::U64,
+            "LINT" => DataType::I64,
+            "REAL" => DataType::F32,
+            "LREAL" => DataType::F64,
+            _ => DataType::Unknown,
+        }
+    }
+}
+
+/// A register value decoded according to its `DataType`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypedValue {
+    Bool(bool),
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    U64(u64),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    /// The data type is unknown, the bytes are left as is.
+    Raw(Vec<u8>),
+}
+
+impl TypedValue {
+    fn decode(data_type: DataType, raw: Vec<u8>) -> Option<Self> {
+        Some(match data_type {
+            DataType::Bool => TypedValue::Bool(*raw.first()? & 1 != 0),
+            DataType::U8 => TypedValue::U8(u8::from_le_bytes(raw.try_into().ok()?)),
+            DataType::I8 => TypedValue::I8(i8::from_le_bytes(raw.try_into().ok()?)),
+            DataType::U16 => TypedValue::U16(u16::from_le_bytes(raw.try_into().ok()?)),
+            DataType::I16 => TypedValue::I16(i16::from_le_bytes(raw.try_into().ok()?)),
+            DataType::U32 => TypedValue::U32(u32::from_le_bytes(raw.try_into().ok()?)),
+            DataType::I32 => TypedValue::I32(i32::from_le_bytes(raw.try_into().ok()?)),
+            DataType::U64 => TypedValue::U64(u64::from_le_bytes(raw.try_into().ok()?)),
+            DataType::I64 => TypedValue::I64(i64::from_le_bytes(raw.try_into().ok()?)),
+            DataType::F32 => TypedValue::F32(f32::from_le_bytes(raw.try_into().ok()?)),
+            DataType::F64 => TypedValue::F64(f64::from_le_bytes(raw.try_into().ok()?)),
+            DataType::Unknown => TypedValue::Raw(raw),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypedWrite {
+    Bool {
+        slave: u16,
+        register: String,
+        index: usize,
+        value: bool,
+    },
+    U8 {
+        slave: u16,
+        register: String,
+        index: usize,
+        value: u8,
+    },
+    I8 {
+        slave: u16,
+        register: String,
+        index: usize,
+        value: i8,
+    },
+    U16 {
+        slave: u16,
+        register: String,
+        index: usize,
+        value: u16,
+    },
+    I16 {
+        slave: u16,
+        register: String,
+        index: usize,
+        value: i16,
+    },
+    U32 {
+        slave: u16,
+        register: String,
+        index: usize,
+        value: u32,
+    },
+    I32 {
+        slave: u16,
+        register: String,
+        index: usize,
+        value: i32,
+    },
+    F32 {
+        slave: u16,
+        register: String,
+        index: usize,
+        value: f32,
+    },
+}
+
+impl TypedWrite {
+    fn target(&self) -> (u16, &str, usize) {
+        match self {
+            TypedWrite::Bool {
+                slave,
+                register,
+                index,
+                ..
+            }
+            | TypedWrite::U8 {
+                slave,
+                register,
+                index,
+                ..
+            }
+            | TypedWrite::I8 {
+                slave,
+                register,
+                index,
+                ..
+            }
+            | TypedWrite::U16 {
+                slave,
+                register,
+                index,
+                ..
+            }
+            | TypedWrite::I16 {
+                slave,
+                register,
+                index,
+                ..
+            }
+            | TypedWrite::U32 {
+                slave,
+                register,
+                index,
+                ..
+            }
+            | TypedWrite::I32 {
+                slave,
+                register,
+                index,
+                ..
+            }
+            | TypedWrite::F32 {
+                slave,
+                register,
+                index,
+                ..
+            } => (*slave, register, *index),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            TypedWrite::Bool { value, .. } => vec![u8::from(*value)],
+            TypedWrite::U8 { value, .. } => value.to_le_bytes().to_vec(),
+            TypedWrite::I8 { value, .. } => value.to_le_bytes().to_vec(),
+            TypedWrite::U16 { value, .. } => value.to_le_bytes().to_vec(),
+            TypedWrite::I16 { value, .. } => value.to_le_bytes().to_vec(),
+            TypedWrite::U32 { value, .. } => value.to_le_bytes().to_vec(),
+            TypedWrite::I32 { value, .. } => value.to_le_bytes().to_vec(),
+            TypedWrite::F32 { value, .. } => value.to_le_bytes().to_vec(),
+        }
+    }
+}
+
+impl EtherCatController {
+    /// Encodes and writes all the values within the same cycle. Nothing is
+    /// written if one of the values does not match its register width.
+    pub fn write_many(&self, writes: &[TypedWrite]) -> Result<(), io::Error> {
+        let mut pdo_writes = Vec::with_capacity(writes.len());
+
+        for write in writes {
+            let (slave_id, register, index) = write.target();
+
+            let layout = self.bit_layout(slave_id, register, index)?;
+            self.check_writable(&layout, || {
+                format!("\"{}\"[{}] of slave {}", register, index, slave_id)
+            })?;
+
+            if let TypedWrite::Bool { value, .. } = write {
+                if layout.bit_len == 1 {
+                    pdo_writes.push(bit_write(slave_id, &layout, &[*value]));
+                    continue;
+                }
+            }
+
+            let reg_addr_range = layout.byte_range();
+            let value = write.to_bytes();
+
+            if value.len() != reg_addr_range.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Register \"{}\"[{}] of slave {} is {} bytes wide, got {} bytes",
+                        register,
+                        index,
+                        slave_id,
+                        reg_addr_range.len(),
+                        value.len()
+                    ),
+                ));
+            }
+
+            pdo_writes.push(PdoWrite::new(slave_id, reg_addr_range, value));
+        }
+
+        self.send_writes(pdo_writes);
+
+        Ok(())
+    }
+
+    /// Reads a register and decodes it according to its data type, from the
+    /// ESI or the configuration.
+    pub fn read_typed(
+        &self,
+        slave_id: u16,
+        register: &str,
+        index: usize,
+    ) -> Result<TypedValue, io::Error> {
+        let layout = self.bit_layout(slave_id, register, index)?;
+
+        if layout.data_type == DataType::Bool {
+            let bits = self.read_bools(slave_id, register, index)?;
+            return Ok(TypedValue::Bool(bits.first().copied().unwrap_or_default()));
+        }
+
+        let raw = self
+            .get_pdo_register_at(slave_id, register, index)
+            .ok_or_else(no_data_error)?;
+        let len = raw.len();
+
+        TypedValue::decode(layout.data_type, raw).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Register \"{}\"[{}] of slave {} is {} bytes wide, not a {:?}",
+                    register, index, slave_id, len, layout.data_type
+                ),
+            )
+        })
+    }
+
+    /// Reads a visible/octet string register, trimming the trailing null
+    /// padding.
+    pub fn read_string(
+        &self,
+        slave_id: u16,
+        register: &str,
+        index: usize,
+    ) -> Result<String, io::Error> {
+        self.bit_layout(slave_id, register, index)?;
+
+        let mut value = self
+            .get_pdo_register_at(slave_id, register, index)
+            .ok_or_else(no_data_error)?;
+
+        let len = value.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+        value.truncate(len);
+
+        String::from_utf8(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Writes a string register, null-padding it to the register width.
+    pub fn write_string(
+        &self,
+        slave_id: u16,
+        register: &str,
+        index: usize,
+        value: &str,
+    ) -> Result<(), io::Error> {
+        let width = self.bit_layout(slave_id, register, index)?.byte_width();
+
+        if value.len() > width {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "\"{}\" is {} bytes long but register \"{}\" is only {} bytes wide",
+                    value,
+                    value.len(),
+                    register,
+                    width
+                ),
+            ));
+        }
+
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.resize(width, 0);
+
+        self.set_pdo_register_at(slave_id, register, index, bytes)
+    }
+
+    /// Reads a register as individual bits, LSB first, one per bit of the
+    /// entry.
+    pub fn read_bools(
+        &self,
+        slave_id: u16,
+        register: &str,
+        index: usize,
+    ) -> Result<Vec<bool>, io::Error> {
+        let layout = self.bit_layout(slave_id, register, index)?;
+        let data = self.data_snapshot.load().ok_or_else(no_data_error)?;
+        let range = bit_byte_range(&layout);
+        let bytes = data.get(range.clone()).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Register at {:?} is beyond the domain ({} bytes)",
+                    range,
+                    data.len()
+                ),
+            )
+        })?;
+
+        Ok((0..layout.bit_len as usize)
+            .map(|i| {
+                let bit = layout.bit_offset as usize + i;
+                bytes[bit / 8] & (1 << (bit % 8)) != 0
+            })
+            .collect())
+    }
+
+    /// Writes a register from individual bits, LSB first. Only the bits of
+    /// the entry are touched, so entries sharing a byte can be written
+    /// independently.
+    pub fn write_bools(
+        &self,
+        slave_id: u16,
+        register: &str,
+        index: usize,
+        values: &[bool],
+    ) -> Result<(), io::Error> {
+        let layout = self.bit_layout(slave_id, register, index)?;
+
+        if values.len() != layout.bit_len as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Register \"{}\"[{}] of slave {} is {} bits wide, got {} values",
+                    register,
+                    index,
+                    slave_id,
+                    layout.bit_len,
+                    values.len()
+                ),
+            ));
+        }
+
+        self.check_writable(&layout, || {
+            format!("\"{}\"[{}] of slave {}", register, index, slave_id)
+        })?;
+
+        self.send_writes(vec![bit_write(slave_id, &layout, values)]);
+
+        Ok(())
+    }
+
+    fn bit_layout(
+        &self,
+        slave_id: u16,
+        register: &str,
+        index: usize,
+    ) -> Result<EntryLayout, io::Error> {
+        self.offset_map()
+            .entry(slave_id, register, index)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!(
+                        "Unknown register \"{}\"[{}] for slave {}",
+                        register, index, slave_id
+                    ),
+                )
+            })
+    }
+}
+
+/// Bytes covered by the entry, including a leading bit offset.
+fn bit_byte_range(layout: &EntryLayout) -> Range<usize> {
+    let len = (layout.bit_offset as usize + layout.bit_len as usize).div_ceil(8);
+    layout.byte_offset..layout.byte_offset + len
+}
+
+/// Write touching only the bits of the entry.
+fn bit_write(slave_id: u16, layout: &EntryLayout, values: &[bool]) -> PdoWrite {
+    let range = bit_byte_range(layout);
+    let mut value = vec![0; range.len()];
+    let mut mask = vec![0; range.len()];
+    for (i, set) in values.iter().enumerate() {
+        let bit = layout.bit_offset as usize + i;
+        mask[bit / 8] |= 1 << (bit % 8);
+        if *set {
+            value[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    PdoWrite {
+        slave_id,
+        range,
+        value,
+        mask: Some(mask),
+    }
+}
+
+fn no_data_error() -> io::Error {
+    io::Error::new(io::ErrorKind::WouldBlock, "No data received yet")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use ethercat::{Offset, PdoEntryIdx};
+
+    use super::*;
+    use crate::{
+        cycle::tests::MockBus,
+        ethercat_controller::tests::{builder, mock_controller},
+        offsets::{tests::layout, Direction, OffsetMap},
+    };
+
+    #[test]
+    fn strings_are_null_padded_and_trimmed() {
+        let mut offsets = OffsetMap::new();
+        offsets.insert(
+            0,
+            "name".to_string(),
+            layout(0x7000, 64, 0, Direction::Output),
+        );
+        let controller = mock_controller(builder().manual_cycle(true), MockBus::new(8), offsets);
+        let name = "name";
+
+        controller.write_string(0, name, 0, "abc").unwrap();
+        controller.step_cycle().unwrap();
+        assert_eq!(
+            controller.get_output_pdo_register(0, name).unwrap(),
+            b"abc\0\0\0\0\0"
+        );
+
+        controller.step_cycle().unwrap();
+        assert_eq!(controller.read_string(0, name, 0).unwrap(), "abc");
+
+        let err = controller
+            .write_string(0, name, 0, "too long a name")
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let err = controller.read_string(0, "unknown", 0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn bools_round_trip_across_byte_boundaries() {
+        let bits = |idx, bit_len, bit| {
+            EntryLayout::new(
+                PdoEntryIdx::new(idx, 0),
+                bit_len,
+                Offset { byte: 0, bit },
+                Direction::Output,
+            )
+        };
+        let mut offsets = OffsetMap::new();
+        offsets.insert(0, "low".to_string(), bits(0x7000, 4, 0));
+        offsets.insert(0, "inputs".to_string(), bits(0x7010, 12, 4));
+        let bus = MockBus::new(2);
+        let sent = Arc::clone(&bus.sent);
+        let controller = mock_controller(builder().manual_cycle(true), bus, offsets);
+
+        let pattern: Vec<bool> = [1, 0, 1, 1, 0, 0, 1, 0, 1, 1, 1, 0]
+            .iter()
+            .map(|bit| *bit == 1)
+            .collect();
+        controller.write_bools(0, "inputs", 0, &pattern).unwrap();
+        controller.step_cycle().unwrap();
+        controller
+            .write_bools(0, "low", 0, &[true, true, false, true])
+            .unwrap();
+        controller.step_cycle().unwrap();
+
+        // Bits 4 to 15, LSB first, with "low" left alone then written alone.
+        assert_eq!(sent.lock().unwrap()[0], [0b1101_0000, 0b0111_0100]);
+        assert_eq!(sent.lock().unwrap()[1], [0b1101_1011, 0b0111_0100]);
+
+        controller.step_cycle().unwrap();
+        assert_eq!(controller.read_bools(0, "inputs", 0).unwrap(), pattern);
+        assert_eq!(
+            controller.read_bools(0, "low", 0).unwrap(),
+            [true, true, false, true]
+        );
+
+        let err = controller
+            .write_bools(0, "inputs", 0, &pattern[1..])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // Whole byte writes would clobber the neighbouring bits.
+        let err = controller.set_pdo_register(0, "low", vec![0]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("write_bools"));
+    }
+
+    #[test]
+    fn same_bytes_decode_by_data_type() {
+        let raw = || vec![0xFE, 0xFF];
+        assert_eq!(
+            TypedValue::decode(DataType::I16, raw()),
+            Some(TypedValue::I16(-2))
+        );
+        assert_eq!(
+            TypedValue::decode(DataType::U16, raw()),
+            Some(TypedValue::U16(0xFFFE))
+        );
+        assert_eq!(
+            TypedValue::decode(DataType::Unknown, raw()),
+            Some(TypedValue::Raw(raw()))
+        );
+        assert_eq!(TypedValue::decode(DataType::I32, raw()), None);
+    }
+
+    #[test]
+    fn registers_are_read_with_their_configured_type() {
+        let mut offsets = OffsetMap::new();
+        for (register, byte) in [("signed", 0), ("unsigned", 2), ("untyped", 4)] {
+            offsets.insert(
+                0,
+                register.to_string(),
+                layout(0x6000 + byte as u16, 16, byte, Direction::Input),
+            );
+        }
+        assert!(offsets.set_data_type(0, "signed", DataType::I16));
+        assert!(offsets.set_data_type(0, "unsigned", DataType::U16));
+        let mut bus = MockBus::new(6);
+        bus.image = vec![0xFE, 0xFF, 0xFE, 0xFF, 0xFE, 0xFF];
+        let controller = mock_controller(builder().manual_cycle(true), bus, offsets);
+        controller.step_cycle().unwrap();
+
+        assert_eq!(
+            controller.read_typed(0, "signed", 0).unwrap(),
+            TypedValue::I16(-2)
+        );
+        assert_eq!(
+            controller.read_typed(0, "unsigned", 0).unwrap(),
+            TypedValue::U16(0xFFFE)
+        );
+        assert_eq!(
+            controller.read_typed(0, "untyped", 0).unwrap(),
+            TypedValue::Raw(vec![0xFE, 0xFF])
+        );
+    }
+
+    #[test]
+    fn typed_writes_are_little_endian() {
+        let write = |value| TypedWrite::I16 {
+            slave: 0,
+            register: "mode".to_string(),
+            index: 0,
+            value,
+        };
+        assert_eq!(write(-2).to_bytes(), [0xFE, 0xFF]);
+        assert_eq!(write(0x0102).to_bytes(), [0x02, 0x01]);
+    }
+
+    #[test]
+    fn mixed_typed_writes_land_in_one_cycle() {
+        let mut offsets = OffsetMap::new();
+        offsets.insert(
+            0,
+            "position".to_string(),
+            layout(0x607A, 32, 0, Direction::Output),
+        );
+        offsets.insert(
+            0,
+            "mode".to_string(),
+            layout(0x6060, 16, 4, Direction::Output),
+        );
+        offsets.insert(
+            1,
+            "enable".to_string(),
+            layout(0x7000, 1, 6, Direction::Output),
+        );
+        let bus = MockBus::new(7);
+        let sent = Arc::clone(&bus.sent);
+        let controller = mock_controller(builder().manual_cycle(true), bus, offsets);
+
+        controller
+            .write_many(&[
+                TypedWrite::I32 {
+                    slave: 0,
+                    register: "position".to_string(),
+                    index: 0,
+                    value: -1,
+                },
+                TypedWrite::U16 {
+                    slave: 0,
+                    register: "mode".to_string(),
+                    index: 0,
+                    value: 0x0203,
+                },
+                TypedWrite::Bool {
+                    slave: 1,
+                    register: "enable".to_string(),
+                    index: 0,
+                    value: true,
+                },
+            ])
+            .unwrap();
+        controller.step_cycle().unwrap();
+
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0], [0xFF, 0xFF, 0xFF, 0xFF, 0x03, 0x02, 0x01]);
+    }
+
+    #[test]
+    fn nothing_is_written_if_a_value_has_the_wrong_width() {
+        let mut offsets = OffsetMap::new();
+        offsets.insert(
+            0,
+            "mode".to_string(),
+            layout(0x6060, 16, 0, Direction::Output),
+        );
+        offsets.insert(
+            0,
+            "target".to_string(),
+            layout(0x607A, 32, 2, Direction::Output),
+        );
+        let bus = MockBus::new(6);
+        let sent = Arc::clone(&bus.sent);
+        let controller = mock_controller(builder().manual_cycle(true), bus, offsets);
+
+        let err = controller
+            .write_many(&[
+                TypedWrite::U16 {
+                    slave: 0,
+                    register: "mode".to_string(),
+                    index: 0,
+                    value: 1,
+                },
+                TypedWrite::U8 {
+                    slave: 0,
+                    register: "target".to_string(),
+                    index: 0,
+                    value: 1,
+                },
+            ])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        controller.step_cycle().unwrap();
+        assert_eq!(sent.lock().unwrap()[0], [0; 6]);
+    }
+}